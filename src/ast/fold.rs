@@ -0,0 +1,123 @@
+//! Rebuilds an AST, changing the nodes a `Folder` is interested in.
+//!
+//! The `fold_*` methods take a node and return the one that replaces it. Their defaults call the
+//! matching `noop_fold_*` function, which folds the children of the node and leaves the node
+//! itself as it is.
+
+use ast;
+use ast::{Statement, Command, CommandOption, Condition};
+
+pub trait Folder {
+    fn fold_statement(&mut self, statement: Statement) -> Statement {
+        noop_fold_statement(self, statement)
+    }
+
+    fn fold_command(&mut self, command: Command) -> Command {
+        noop_fold_command(self, command)
+    }
+
+    fn fold_options(&mut self, options: Vec<CommandOption>) -> Vec<CommandOption> {
+        options
+    }
+
+    fn fold_condition(&mut self, condition: Condition) -> Condition {
+        noop_fold_condition(self, condition)
+    }
+}
+
+pub fn noop_fold_statement<F: Folder>(folder: &mut F, statement: Statement) -> Statement {
+    match statement {
+        ast::Statements(box stmts) => ast::Statements(box stmts.into_iter().map(|stmt| folder.fold_statement(stmt)).collect()),
+        ast::Fold(name, box body) => ast::Fold(name, box folder.fold_statement(body)),
+        ast::Cmd(command, options) => {
+            let command = folder.fold_command(command);
+            ast::Cmd(command, folder.fold_options(options))
+        },
+        ast::If(condition, box body, box elsebody) => {
+            let condition = folder.fold_condition(condition);
+            let body = folder.fold_statement(body);
+            ast::If(condition, box body, box folder.fold_statement(elsebody))
+        },
+        ast::For(var, values, box body) => ast::For(var, values, box folder.fold_statement(body)),
+        ast::While(condition, box body) => {
+            let condition = folder.fold_condition(condition);
+            ast::While(condition, box folder.fold_statement(body))
+        },
+        ast::Function(name, params, box body) => ast::Function(name, params, box folder.fold_statement(body)),
+        ast::Traced(source, box body) => ast::Traced(source, box folder.fold_statement(body)),
+        statement => statement,
+    }
+}
+
+/// Commands don't contain other nodes, so this returns the command unchanged.
+pub fn noop_fold_command<F: Folder>(_folder: &mut F, command: Command) -> Command {
+    command
+}
+
+pub fn noop_fold_condition<F: Folder>(folder: &mut F, condition: Condition) -> Condition {
+    match condition {
+        ast::CmdCond(command) => ast::CmdCond(folder.fold_command(command)),
+        ast::And(box cond1, box cond2) => {
+            let cond1 = folder.fold_condition(cond1);
+            ast::And(box cond1, box folder.fold_condition(cond2))
+        },
+        ast::Or(box cond1, box cond2) => {
+            let cond1 = folder.fold_condition(cond1);
+            ast::Or(box cond1, box folder.fold_condition(cond2))
+        },
+        ast::Not(box cond) => ast::Not(box folder.fold_condition(cond)),
+        condition => condition,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Folder;
+    use ast;
+    use bash::ToBash;
+
+    /// Times every command.
+    struct Timing;
+
+    impl Folder for Timing {
+        fn fold_options(&mut self, mut options: Vec<ast::CommandOption>) -> Vec<ast::CommandOption> {
+            options.push(ast::TimingOption);
+            options
+        }
+    }
+
+    /// Runs raw commands that use `sudo` without it.
+    struct NoSudo;
+
+    impl Folder for NoSudo {
+        fn fold_command(&mut self, command: ast::Command) -> ast::Command {
+            match command {
+                ast::Raw(ref cmd) if cmd.as_slice().starts_with("sudo ") => ast::Raw(cmd.as_slice().slice_from(5).to_string()),
+                command => command,
+            }
+        }
+    }
+
+    fn script() -> ast::Statement {
+        ast_block! {
+            format_cmd!([EchoOption], "sudo apt-get install foo");
+            ast_if!(!ast::CmdCond(ast::Raw("sudo true".to_string())) {
+                ast::Fold("script".to_string(), box format_cmd!("make test"));
+            });
+        }
+    }
+
+    #[test]
+    fn test_fold_options() {
+        let bash = Timing.fold_statement(script()).to_bash().unwrap();
+
+        assert_eq!("travis_cmd 'sudo apt-get install foo' --echo --timing\nif { ! sudo true; }; then\n  travis_fold start script\n  travis_cmd 'make test' --timing\n  travis_fold end script\nfi", bash.as_slice());
+    }
+
+    #[test]
+    fn test_fold_commands() {
+        let bash = NoSudo.fold_statement(script()).to_bash().unwrap();
+
+        assert_eq!("travis_cmd 'apt-get install foo' --echo\nif { ! true; }; then\n  travis_fold start script\n  travis_cmd 'make test'\n  travis_fold end script\nfi", bash.as_slice());
+    }
+}
@@ -0,0 +1,538 @@
+//! Converts the AST to and from JSON.
+//!
+//! Every node is an object whose `type` key says what kind of node it is. The other keys hold the
+//! fields of the node:
+//!
+//! ```text
+//! statement = {"type": "statements", "statements": [statement]}
+//!           | {"type": "fold", "name": string, "body": statement}
+//!           | {"type": "cmd", "command": command, "options": [option]}
+//!           | {"type": "if", "condition": condition, "then": statement, "else": statement}
+//!           | {"type": "for", "var": string, "values": [string], "body": statement}
+//!           | {"type": "while", "condition": condition, "body": statement}
+//!           | {"type": "function", "name": string, "params": [string], "body": statement}
+//!           | {"type": "assign", "name": string, "value": string}
+//!           | {"type": "local", "name": string, "value": string}
+//!           | {"type": "traced", "source": string, "body": statement}
+//!           | {"type": "stage", "name": string}
+//!           | {"type": "noop"}
+//!
+//! command   = {"type": "raw", "command": string}
+//!           | {"type": "echo", "text": string}
+//!           | {"type": "newline"}
+//!           | {"type": "envset", "name": string, "value": string}
+//!           | {"type": "cd" | "mkdir" | "removefile", "path": path}
+//!           | {"type": "putfile", "path": path, "contents": base64 string, "compress": boolean, "mode": integer | null, "append": boolean}
+//!           | {"type": "copyfile" | "movefile", "from": path, "to": path}
+//!           | {"type": "call", "name": string, "args": [string]}
+//!           | {"type": "exec", "commands": [simple command], "background": boolean}
+//!
+//! simple command = {"argv": [string], "redirects": [redirect]}
+//!
+//! redirect  = {"type": "stdout" | "append_stdout" | "stderr" | "stdin", "path": path}
+//!           | {"type": "stderr_to_stdout"}
+//!
+//! condition = {"type": "exists" | "is_directory" | "is_file", "path": path}
+//!           | {"type": "cmd", "command": command}
+//!           | {"type": "is_set", "name": string}
+//!           | {"type": "var_equals", "name": string, "value": string}
+//!           | {"type": "str_equals", "left": string, "right": string}
+//!           | {"type": "matches", "string": string, "regex": string}
+//!           | {"type": "command_available", "command": string}
+//!           | {"type": "last_succeeded"}
+//!           | {"type": "and" | "or", "left": condition, "right": condition}
+//!           | {"type": "not", "condition": condition}
+//!
+//! option    = {"type": "echo" | "assert" | "result" | "retry" | "timing"}
+//!           | {"type": "display", "display": string}
+//!           | {"type": "retry_policy", "attempts": integer, "backoff": "fixed", "delay": integer}
+//!           | {"type": "retry_policy", "attempts": integer, "backoff": "exponential", "delay": integer, "max_delay": integer}
+//!
+//! path      = string
+//!           | {"base64": base64 string}
+//! ```
+//!
+//! Paths are strings if they're UTF-8, and their bytes in base64 otherwise.
+
+use ast;
+use ast::{Statement, Command, CommandOption, Condition, Pipeline, PutfileOptions, SimpleCommand, Redirect, RetryPolicy};
+use serialize::base64;
+use serialize::base64::{FromBase64, ToBase64};
+use serialize::json;
+use serialize::json::{Json, ToJson};
+use std::collections::TreeMap;
+
+fn node(kind: &str, fields: Vec<(&str, Json)>) -> Json {
+    let mut object = TreeMap::new();
+    object.insert("type".to_string(), kind.to_string().to_json());
+    for (key, value) in fields.into_iter() {
+        object.insert(key.to_string(), value);
+    }
+    json::Object(object)
+}
+
+fn path_json(path: &Path) -> Json {
+    match path.as_str() {
+        Some(string) => string.to_string().to_json(),
+        None => {
+            let mut object = TreeMap::new();
+            object.insert("base64".to_string(), path.as_vec().to_base64(base64::STANDARD).to_json());
+            json::Object(object)
+        },
+    }
+}
+
+impl ToJson for Statement {
+    fn to_json(&self) -> Json {
+        match *self {
+            ast::Statements(ref stmts) => node("statements", vec![("statements", (**stmts).to_json())]),
+            ast::Fold(ref name, ref body) => node("fold", vec![("name", name.to_json()), ("body", (**body).to_json())]),
+            ast::Cmd(ref command, ref options) => node("cmd", vec![("command", command.to_json()), ("options", options.to_json())]),
+            ast::If(ref condition, ref body, ref elsebody) => node("if", vec![("condition", condition.to_json()), ("then", (**body).to_json()), ("else", (**elsebody).to_json())]),
+            ast::For(ref var, ref values, ref body) => node("for", vec![("var", var.to_json()), ("values", values.to_json()), ("body", (**body).to_json())]),
+            ast::While(ref condition, ref body) => node("while", vec![("condition", condition.to_json()), ("body", (**body).to_json())]),
+            ast::Function(ref name, ref params, ref body) => node("function", vec![("name", name.to_json()), ("params", params.to_json()), ("body", (**body).to_json())]),
+            ast::Assign(ref name, ref value) => node("assign", vec![("name", name.to_json()), ("value", value.to_json())]),
+            ast::Local(ref name, ref value) => node("local", vec![("name", name.to_json()), ("value", value.to_json())]),
+            ast::Traced(ref source, ref body) => node("traced", vec![("source", source.to_json()), ("body", (**body).to_json())]),
+            ast::Stage(ref name) => node("stage", vec![("name", name.to_json())]),
+            ast::Noop => node("noop", vec![]),
+        }
+    }
+}
+
+impl ToJson for Command {
+    fn to_json(&self) -> Json {
+        match *self {
+            ast::Raw(ref command) => node("raw", vec![("command", command.to_json())]),
+            ast::Echo(ref text) => node("echo", vec![("text", text.to_json())]),
+            ast::Newline => node("newline", vec![]),
+            ast::Envset(ref name, ref value) => node("envset", vec![("name", name.to_json()), ("value", value.to_json())]),
+            ast::Cd(ref path) => node("cd", vec![("path", path_json(path))]),
+            ast::Putfile(ref path, ref contents, ref options) => node("putfile", vec![
+                ("path", path_json(path)),
+                ("contents", contents.as_slice().to_base64(base64::STANDARD).to_json()),
+                ("compress", options.compress.to_json()),
+                ("mode", options.mode.map_or(json::Null, |mode| (mode as u64).to_json())),
+                ("append", options.append.to_json()),
+            ]),
+            ast::Mkdir(ref path) => node("mkdir", vec![("path", path_json(path))]),
+            ast::Copyfile(ref from, ref to) => node("copyfile", vec![("from", path_json(from)), ("to", path_json(to))]),
+            ast::Movefile(ref from, ref to) => node("movefile", vec![("from", path_json(from)), ("to", path_json(to))]),
+            ast::Removefile(ref path) => node("removefile", vec![("path", path_json(path))]),
+            ast::Call(ref name, ref args) => node("call", vec![("name", name.to_json()), ("args", args.to_json())]),
+            ast::Exec(ref pipeline) => node("exec", vec![("commands", pipeline.commands.to_json()), ("background", pipeline.background.to_json())]),
+        }
+    }
+}
+
+impl ToJson for SimpleCommand {
+    fn to_json(&self) -> Json {
+        let mut object = TreeMap::new();
+        object.insert("argv".to_string(), self.argv.to_json());
+        object.insert("redirects".to_string(), self.redirects.to_json());
+        json::Object(object)
+    }
+}
+
+impl ToJson for Redirect {
+    fn to_json(&self) -> Json {
+        match *self {
+            ast::RedirectStdout(ref path) => node("stdout", vec![("path", path_json(path))]),
+            ast::AppendStdout(ref path) => node("append_stdout", vec![("path", path_json(path))]),
+            ast::RedirectStderr(ref path) => node("stderr", vec![("path", path_json(path))]),
+            ast::StderrToStdout => node("stderr_to_stdout", vec![]),
+            ast::RedirectStdin(ref path) => node("stdin", vec![("path", path_json(path))]),
+        }
+    }
+}
+
+impl ToJson for Condition {
+    fn to_json(&self) -> Json {
+        match *self {
+            ast::Exists(ref path) => node("exists", vec![("path", path_json(path))]),
+            ast::IsDirectory(ref path) => node("is_directory", vec![("path", path_json(path))]),
+            ast::IsFile(ref path) => node("is_file", vec![("path", path_json(path))]),
+            ast::CmdCond(ref command) => node("cmd", vec![("command", command.to_json())]),
+            ast::IsSet(ref name) => node("is_set", vec![("name", name.to_json())]),
+            ast::VarEquals(ref name, ref value) => node("var_equals", vec![("name", name.to_json()), ("value", value.to_json())]),
+            ast::StrEquals(ref left, ref right) => node("str_equals", vec![("left", left.to_json()), ("right", right.to_json())]),
+            ast::Matches(ref string, ref regex) => node("matches", vec![("string", string.to_json()), ("regex", regex.to_json())]),
+            ast::CommandAvailable(ref command) => node("command_available", vec![("command", command.to_json())]),
+            ast::LastSucceeded => node("last_succeeded", vec![]),
+            ast::And(ref left, ref right) => node("and", vec![("left", (**left).to_json()), ("right", (**right).to_json())]),
+            ast::Or(ref left, ref right) => node("or", vec![("left", (**left).to_json()), ("right", (**right).to_json())]),
+            ast::Not(ref condition) => node("not", vec![("condition", (**condition).to_json())]),
+        }
+    }
+}
+
+impl ToJson for CommandOption {
+    fn to_json(&self) -> Json {
+        match *self {
+            ast::EchoOption => node("echo", vec![]),
+            ast::AssertOption => node("assert", vec![]),
+            ast::DisplayOption(ref display) => node("display", vec![("display", display.to_json())]),
+            ast::ResultOption => node("result", vec![]),
+            ast::RetryOption => node("retry", vec![]),
+            ast::TimingOption => node("timing", vec![]),
+            ast::RetryPolicyOption(ref policy) => {
+                let mut fields = vec![("attempts", policy.attempts.to_json())];
+                match policy.backoff {
+                    ast::FixedBackoff(delay) => {
+                        fields.push(("backoff", "fixed".to_string().to_json()));
+                        fields.push(("delay", delay.to_json()));
+                    },
+                    ast::ExponentialBackoff(delay, max_delay) => {
+                        fields.push(("backoff", "exponential".to_string().to_json()));
+                        fields.push(("delay", delay.to_json()));
+                        fields.push(("max_delay", max_delay.to_json()));
+                    },
+                }
+                node("retry_policy", fields)
+            },
+        }
+    }
+}
+
+type Decoder<T> = fn(&Json, &str) -> json::DecodeResult<T>;
+
+fn expected_error(path: &str, expected: &str, found: &Json) -> json::DecoderError {
+    json::ApplicationError(format!("{}: expected {}, got {}", path, expected, found))
+}
+
+fn expect_object<'a>(j: &'a Json, path: &str) -> json::DecodeResult<&'a json::Object> {
+    match *j {
+        json::Object(ref object) => Ok(object),
+        _ => Err(expected_error(path, "object", j)),
+    }
+}
+
+/// Decodes the value of `key`, which is found at `path.key`.
+fn field<T>(object: &json::Object, path: &str, key: &str, decode: Decoder<T>) -> json::DecodeResult<T> {
+    match object.find(&key.to_string()) {
+        Some(j) => decode(j, format!("{}.{}", path, key).as_slice()),
+        None => Err(json::MissingFieldError(format!("{}.{}", path, key))),
+    }
+}
+
+fn list<T>(j: &Json, path: &str, decode: Decoder<T>) -> json::DecodeResult<Vec<T>> {
+    let list = match *j {
+        json::List(ref list) => list,
+        _ => return Err(expected_error(path, "list", j)),
+    };
+
+    let mut result = Vec::with_capacity(list.len());
+    for (i, element) in list.iter().enumerate() {
+        result.push(try!(decode(element, format!("{}[{}]", path, i).as_slice())));
+    }
+    Ok(result)
+}
+
+fn string(j: &Json, path: &str) -> json::DecodeResult<String> {
+    j.as_string().map(|s| s.to_string()).ok_or(expected_error(path, "string", j))
+}
+
+fn strings(j: &Json, path: &str) -> json::DecodeResult<Vec<String>> {
+    list(j, path, string)
+}
+
+fn file_path(j: &Json, path: &str) -> json::DecodeResult<Path> {
+    let bytes = match *j {
+        json::String(ref string) => string.as_bytes().to_vec(),
+        json::Object(ref object) => try!(field(object, path, "base64", base64_bytes)),
+        _ => return Err(expected_error(path, "string or object", j)),
+    };
+    Path::new_opt(bytes).ok_or(expected_error(path, "path without NUL bytes", j))
+}
+
+fn unsigned(j: &Json, path: &str) -> json::DecodeResult<u64> {
+    j.as_u64().ok_or(expected_error(path, "unsigned integer", j))
+}
+
+fn boolean(j: &Json, path: &str) -> json::DecodeResult<bool> {
+    j.as_boolean().ok_or(expected_error(path, "boolean", j))
+}
+
+/// Permissions like `0o644`, or `null` to leave them to the umask.
+fn file_mode(j: &Json, path: &str) -> json::DecodeResult<Option<u32>> {
+    match *j {
+        json::Null => Ok(None),
+        _ => match j.as_u64() {
+            Some(mode) if mode <= 0o7777 => Ok(Some(mode as u32)),
+            _ => Err(expected_error(path, "file mode", j)),
+        },
+    }
+}
+
+fn base64_bytes(j: &Json, path: &str) -> json::DecodeResult<Vec<u8>> {
+    let encoded = try!(string(j, path));
+    encoded.as_slice().from_base64().map_err(|_| expected_error(path, "base64", j))
+}
+
+fn unknown_type(path: &str, kind: &str, found: String) -> json::DecoderError {
+    json::ApplicationError(format!("{}.type: unknown {} type \"{}\"", path, kind, found))
+}
+
+impl Statement {
+    /// Decodes a statement in the format described in the module documentation. `path` is where
+    /// the statement is in the document, and is used in error messages.
+    pub fn from_json(j: &Json, path: &str) -> json::DecodeResult<Statement> {
+        let object = try!(expect_object(j, path));
+        let kind = try!(field(object, path, "type", string));
+
+        Ok(match kind.as_slice() {
+            "statements" => ast::Statements(box try!(field(object, path, "statements", statements))),
+            "fold" => ast::Fold(try!(field(object, path, "name", string)), box try!(field(object, path, "body", Statement::from_json))),
+            "cmd" => ast::Cmd(try!(field(object, path, "command", Command::from_json)), try!(field(object, path, "options", options))),
+            "if" => ast::If(try!(field(object, path, "condition", Condition::from_json)),
+                            box try!(field(object, path, "then", Statement::from_json)),
+                            box try!(field(object, path, "else", Statement::from_json))),
+            "for" => ast::For(try!(field(object, path, "var", string)), try!(field(object, path, "values", strings)), box try!(field(object, path, "body", Statement::from_json))),
+            "while" => ast::While(try!(field(object, path, "condition", Condition::from_json)), box try!(field(object, path, "body", Statement::from_json))),
+            "function" => ast::Function(try!(field(object, path, "name", string)), try!(field(object, path, "params", strings)), box try!(field(object, path, "body", Statement::from_json))),
+            "assign" => ast::Assign(try!(field(object, path, "name", string)), try!(field(object, path, "value", string))),
+            "local" => ast::Local(try!(field(object, path, "name", string)), try!(field(object, path, "value", string))),
+            "traced" => ast::Traced(try!(field(object, path, "source", string)), box try!(field(object, path, "body", Statement::from_json))),
+            "stage" => ast::Stage(try!(field(object, path, "name", string))),
+            "noop" => ast::Noop,
+            _ => return Err(unknown_type(path, "statement", kind)),
+        })
+    }
+}
+
+fn statements(j: &Json, path: &str) -> json::DecodeResult<Vec<Statement>> {
+    list(j, path, Statement::from_json)
+}
+
+fn options(j: &Json, path: &str) -> json::DecodeResult<Vec<CommandOption>> {
+    list(j, path, CommandOption::from_json)
+}
+
+impl Command {
+    pub fn from_json(j: &Json, path: &str) -> json::DecodeResult<Command> {
+        let object = try!(expect_object(j, path));
+        let kind = try!(field(object, path, "type", string));
+
+        Ok(match kind.as_slice() {
+            "raw" => ast::Raw(try!(field(object, path, "command", string))),
+            "echo" => ast::Echo(try!(field(object, path, "text", string))),
+            "newline" => ast::Newline,
+            "envset" => ast::Envset(try!(field(object, path, "name", string)), try!(field(object, path, "value", string))),
+            "cd" => ast::Cd(try!(field(object, path, "path", file_path))),
+            "putfile" => ast::Putfile(try!(field(object, path, "path", file_path)), try!(field(object, path, "contents", base64_bytes)), PutfileOptions {
+                compress: try!(field(object, path, "compress", boolean)),
+                mode: try!(field(object, path, "mode", file_mode)),
+                append: try!(field(object, path, "append", boolean)),
+            }),
+            "mkdir" => ast::Mkdir(try!(field(object, path, "path", file_path))),
+            "copyfile" => ast::Copyfile(try!(field(object, path, "from", file_path)), try!(field(object, path, "to", file_path))),
+            "movefile" => ast::Movefile(try!(field(object, path, "from", file_path)), try!(field(object, path, "to", file_path))),
+            "removefile" => ast::Removefile(try!(field(object, path, "path", file_path))),
+            "call" => ast::Call(try!(field(object, path, "name", string)), try!(field(object, path, "args", strings))),
+            "exec" => ast::Exec(Pipeline {
+                commands: try!(field(object, path, "commands", simple_commands)),
+                background: try!(field(object, path, "background", boolean)),
+            }),
+            _ => return Err(unknown_type(path, "command", kind)),
+        })
+    }
+}
+
+fn simple_commands(j: &Json, path: &str) -> json::DecodeResult<Vec<SimpleCommand>> {
+    list(j, path, SimpleCommand::from_json)
+}
+
+impl SimpleCommand {
+    pub fn from_json(j: &Json, path: &str) -> json::DecodeResult<SimpleCommand> {
+        let object = try!(expect_object(j, path));
+
+        Ok(SimpleCommand {
+            argv: try!(field(object, path, "argv", strings)),
+            redirects: try!(field(object, path, "redirects", redirects)),
+        })
+    }
+}
+
+fn redirects(j: &Json, path: &str) -> json::DecodeResult<Vec<Redirect>> {
+    list(j, path, Redirect::from_json)
+}
+
+impl Redirect {
+    pub fn from_json(j: &Json, path: &str) -> json::DecodeResult<Redirect> {
+        let object = try!(expect_object(j, path));
+        let kind = try!(field(object, path, "type", string));
+
+        Ok(match kind.as_slice() {
+            "stdout" => ast::RedirectStdout(try!(field(object, path, "path", file_path))),
+            "append_stdout" => ast::AppendStdout(try!(field(object, path, "path", file_path))),
+            "stderr" => ast::RedirectStderr(try!(field(object, path, "path", file_path))),
+            "stderr_to_stdout" => ast::StderrToStdout,
+            "stdin" => ast::RedirectStdin(try!(field(object, path, "path", file_path))),
+            _ => return Err(unknown_type(path, "redirect", kind)),
+        })
+    }
+}
+
+impl Condition {
+    pub fn from_json(j: &Json, path: &str) -> json::DecodeResult<Condition> {
+        let object = try!(expect_object(j, path));
+        let kind = try!(field(object, path, "type", string));
+
+        Ok(match kind.as_slice() {
+            "exists" => ast::Exists(try!(field(object, path, "path", file_path))),
+            "is_directory" => ast::IsDirectory(try!(field(object, path, "path", file_path))),
+            "is_file" => ast::IsFile(try!(field(object, path, "path", file_path))),
+            "cmd" => ast::CmdCond(try!(field(object, path, "command", Command::from_json))),
+            "is_set" => ast::IsSet(try!(field(object, path, "name", string))),
+            "var_equals" => ast::VarEquals(try!(field(object, path, "name", string)), try!(field(object, path, "value", string))),
+            "str_equals" => ast::StrEquals(try!(field(object, path, "left", string)), try!(field(object, path, "right", string))),
+            "matches" => ast::Matches(try!(field(object, path, "string", string)), try!(field(object, path, "regex", string))),
+            "command_available" => ast::CommandAvailable(try!(field(object, path, "command", string))),
+            "last_succeeded" => ast::LastSucceeded,
+            "and" => ast::And(box try!(field(object, path, "left", Condition::from_json)), box try!(field(object, path, "right", Condition::from_json))),
+            "or" => ast::Or(box try!(field(object, path, "left", Condition::from_json)), box try!(field(object, path, "right", Condition::from_json))),
+            "not" => ast::Not(box try!(field(object, path, "condition", Condition::from_json))),
+            _ => return Err(unknown_type(path, "condition", kind)),
+        })
+    }
+}
+
+impl CommandOption {
+    pub fn from_json(j: &Json, path: &str) -> json::DecodeResult<CommandOption> {
+        let object = try!(expect_object(j, path));
+        let kind = try!(field(object, path, "type", string));
+
+        Ok(match kind.as_slice() {
+            "echo" => ast::EchoOption,
+            "assert" => ast::AssertOption,
+            "display" => ast::DisplayOption(try!(field(object, path, "display", string))),
+            "result" => ast::ResultOption,
+            "retry" => ast::RetryOption,
+            "timing" => ast::TimingOption,
+            "retry_policy" => {
+                let delay = try!(field(object, path, "delay", unsigned));
+                let backoff = try!(field(object, path, "backoff", string));
+                ast::RetryPolicyOption(RetryPolicy {
+                    attempts: try!(field(object, path, "attempts", unsigned)),
+                    backoff: match backoff.as_slice() {
+                        "fixed" => ast::FixedBackoff(delay),
+                        "exponential" => ast::ExponentialBackoff(delay, try!(field(object, path, "max_delay", unsigned))),
+                        _ => return Err(json::ApplicationError(format!("{}.backoff: expected \"fixed\" or \"exponential\", got \"{}\"", path, backoff))),
+                    },
+                })
+            },
+            _ => return Err(unknown_type(path, "option", kind)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ast;
+    use bash::ToBash;
+    use serialize::json;
+    use serialize::json::ToJson;
+
+    fn round_trip(statement: &ast::Statement) -> ast::Statement {
+        let encoded = statement.to_json().to_string();
+        match ast::Statement::from_json(&json::from_str(encoded.as_slice()).unwrap(), "ast") {
+            Ok(decoded) => decoded,
+            Err(e) => panic!("couldn't decode {}: {}", encoded, e),
+        }
+    }
+
+    fn decode_error(input: &str) -> String {
+        match ast::Statement::from_json(&json::from_str(input).unwrap(), "ast") {
+            Err(json::ApplicationError(message)) => message,
+            Err(json::MissingFieldError(field)) => format!("missing {}", field),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected {} to be invalid", input),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let statement = ast::Fold("install".to_string(), box format_cmd!([EchoOption|RetryOption], "make deps"));
+
+        assert_eq!("{\"body\":{\"command\":{\"command\":\"make deps\",\"type\":\"raw\"},\"options\":[{\"type\":\"echo\"},{\"type\":\"retry\"}],\"type\":\"cmd\"},\"name\":\"install\",\"type\":\"fold\"}",
+                   statement.to_json().to_string().as_slice());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let policy = ast::RetryPolicy { attempts: 5, backoff: ast::ExponentialBackoff(2, 30) };
+        let pipeline = ast::Pipeline::new(vec![
+            ast::SimpleCommand::new(&["cat", "log"]).redirect(ast::RedirectStdin(Path::new("in"))),
+            ast::SimpleCommand::new(&["tee", "out"]).redirect(ast::AppendStdout(Path::new("a"))).redirect(ast::RedirectStderr(Path::new("b"))).redirect(ast::StderrToStdout).redirect(ast::RedirectStdout(Path::new("c"))),
+        ]).background();
+
+        let statement = ast_block! {
+            ast::Fold("setup".to_string(), box ast_block! {
+                cmd!(ast::Newline);
+                cmd!(ast::Echo("hello".to_string()));
+                ast::Cmd(ast::Envset("A".to_string(), "1".to_string()), vec![ast::EchoOption, ast::DisplayOption("export A=[secure]".to_string())]);
+                cmd!(ast::Cd(Path::new("build")));
+                cmd!(ast::Putfile(Path::new("file"), b"\x00binary\xff".to_vec(), ast::PutfileOptions::default()));
+                cmd!(ast::Putfile(Path::new("key"), b"secret".to_vec(), ast::PutfileOptions::default().compress().mode(0o600).append()));
+                cmd!(ast::Mkdir(Path::new("dir")));
+                cmd!(ast::Copyfile(Path::new("a"), Path::new("b")));
+                cmd!(ast::Movefile(Path::new("b"), Path::new("c")));
+                cmd!(ast::Removefile(Path::new("c")));
+            });
+            ast::Function("greet".to_string(), vec!["name".to_string()], box ast::Local("x".to_string(), "y".to_string()));
+            ast::Traced("config.script[0]".to_string(), box format_cmd!("make"));
+            ast::Stage("script".to_string());
+            ast::Cmd(ast::Call("greet".to_string(), vec!["world".to_string()]), vec![ast::AssertOption, ast::ResultOption, ast::TimingOption, ast::RetryPolicyOption(policy)]);
+            cmd!(ast::Exec(pipeline));
+            ast::For("i".to_string(), vec!["1".to_string(), "2".to_string()], box ast::Assign("j".to_string(), "$i".to_string()));
+            ast::While(ast::And(box ast::IsSet("A".to_string()), box ast::Or(box ast::VarEquals("A".to_string(), "1".to_string()), box ast::StrEquals("a".to_string(), "b".to_string()))), box ast::Noop);
+            ast_if!(!ast::Matches("$A".to_string(), "^1$".to_string()) {
+                ast::Cmd(ast::Raw("make".to_string()), vec![ast::RetryPolicyOption(ast::RetryPolicy { attempts: 2, backoff: ast::FixedBackoff(1) })]);
+            } else {
+                ast_if!(ast::And(box ast::CommandAvailable("make".to_string()), box ast::LastSucceeded) {
+                    ast_if!(ast::And(box ast::Exists(Path::new("x")), box ast::And(box ast::IsFile(Path::new("y")), box ast::IsDirectory(Path::new("z")))) {
+                        ast_if!(ast::CmdCond(ast::Raw("true".to_string())) { ast::Noop; });
+                    });
+                });
+            });
+        };
+
+        let decoded = round_trip(&statement);
+
+        assert!(decoded == statement, "expected {} to decode to {}", decoded.to_bash().unwrap(), statement.to_bash().unwrap());
+    }
+
+    #[test]
+    fn test_paths_that_arent_utf8() {
+        let statement = ast_block! {
+            cmd!(ast::Cd(Path::new(b"caf\xc3")));
+            cmd!(ast::Exec(ast::Pipeline::new(vec![ast::SimpleCommand::new(&["cat"]).redirect(ast::RedirectStdin(Path::new(b"\xff")))])));
+        };
+
+        assert_eq!("{\"command\":{\"path\":{\"base64\":\"Y2Fmww==\"},\"type\":\"cd\"},\"options\":[],\"type\":\"cmd\"}",
+                   cmd!(ast::Cd(Path::new(b"caf\xc3"))).to_json().to_string().as_slice());
+        assert!(round_trip(&statement) == statement);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!("ast: expected object, got []", decode_error("[]").as_slice());
+        assert_eq!("missing ast.type", decode_error("{}").as_slice());
+        assert_eq!("ast.type: unknown statement type \"loop\"", decode_error("{\"type\":\"loop\"}").as_slice());
+        assert_eq!("ast.statements[1].command.type: unknown command type \"rm\"",
+                   decode_error("{\"type\":\"statements\",\"statements\":[{\"type\":\"noop\"},{\"type\":\"cmd\",\"command\":{\"type\":\"rm\"},\"options\":[]}]}").as_slice());
+        assert_eq!("ast.options[0].attempts: expected unsigned integer, got \"3\"",
+                   decode_error("{\"type\":\"cmd\",\"command\":{\"type\":\"newline\"},\"options\":[{\"type\":\"retry_policy\",\"attempts\":\"3\",\"backoff\":\"fixed\",\"delay\":1}]}").as_slice());
+        assert_eq!("ast.command.contents: expected base64, got \"!\"",
+                   decode_error("{\"type\":\"cmd\",\"command\":{\"type\":\"putfile\",\"path\":\"a\",\"contents\":\"!\"},\"options\":[]}").as_slice());
+        assert_eq!("ast.command.path: expected string or object, got 1",
+                   decode_error("{\"type\":\"cmd\",\"command\":{\"type\":\"cd\",\"path\":1},\"options\":[]}").as_slice());
+        assert_eq!("ast.command.path.base64: expected base64, got \"!\"",
+                   decode_error("{\"type\":\"cmd\",\"command\":{\"type\":\"cd\",\"path\":{\"base64\":\"!\"}},\"options\":[]}").as_slice());
+        assert!(decode_error("{\"type\":\"cmd\",\"command\":{\"type\":\"cd\",\"path\":\"a\\u0000\"},\"options\":[]}").as_slice()
+                .starts_with("ast.command.path: expected path without NUL bytes, got "));
+        assert_eq!("ast.command.mode: expected file mode, got 4096",
+                   decode_error("{\"type\":\"cmd\",\"command\":{\"type\":\"putfile\",\"path\":\"a\",\"contents\":\"\",\"compress\":false,\"mode\":4096,\"append\":false},\"options\":[]}").as_slice());
+    }
+}
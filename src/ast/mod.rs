@@ -3,16 +3,32 @@
 use std::path::Path;
 use std::ops;
 
-#[deriving(Clone)]
+#[deriving(Clone, PartialEq)]
 pub enum Statement {
     Statements(Box<Vec<Statement>>),
     Fold(String, Box<Statement>),
     Cmd(Command, Vec<CommandOption>),
     If(Condition, Box<Statement>, Box<Statement>),
+    /// Run the body once for every value, with the variable set to the value.
+    For(String, Vec<String>, Box<Statement>),
+    /// Run the body for as long as the condition holds.
+    While(Condition, Box<Statement>),
+    /// Define a shell function with named parameters. It can be run with the `Call` command.
+    Function(String, Vec<String>, Box<Statement>),
+    /// Set a shell variable without exporting it.
+    Assign(String, String),
+    /// Set a variable that is only visible until the function it's set in returns.
+    Local(String, String),
+    /// Records where a statement came from, like `components::git` or `config.script[2]`. The
+    /// statement runs as if it wasn't wrapped.
+    Traced(String, Box<Statement>),
+    /// Marks the start of a phase of the build, like `install`, by exporting `TRAVIS_STAGE`. The
+    /// helpers in the header say which stage a failing command was in.
+    Stage(String),
     Noop
 }
 
-#[deriving(Clone, Show)]
+#[deriving(Clone, PartialEq, Show)]
 pub enum CommandOption {
     /// Print out the command before running it.
     EchoOption,
@@ -21,32 +37,181 @@ pub enum CommandOption {
     AssertOption,
 
     /// The string to print before the command. Only makes sense with EchoOption. By default this is `$ the-command`.
-    DisplayOption(String)
+    DisplayOption(String),
+
+    /// Report the exit status of the command with `travis_result` instead of failing the script.
+    ResultOption,
+
+    /// Run the command again if it fails, to work around flaky network connections. `Putfile`
+    /// can't be retried, since it reads the contents from stdin.
+    RetryOption,
+
+    /// Print how long the command took to run.
+    TimingOption,
+
+    /// Like `RetryOption`, but with the given policy instead of the one the build was started with.
+    RetryPolicyOption(RetryPolicy),
+}
+
+/// How many times `travis_retry` runs a failing command, and how long it waits in between.
+#[deriving(Clone, PartialEq, Show)]
+pub struct RetryPolicy {
+    /// The number of times the command is run, including the first attempt.
+    pub attempts: u64,
+    pub backoff: Backoff,
+}
+
+/// The time to wait between two attempts, in seconds.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Backoff {
+    /// Wait the same time after every attempt.
+    FixedBackoff(u64),
+
+    /// Wait the first delay after the first attempt, and twice as long after each following one,
+    /// but never longer than the second delay.
+    ExponentialBackoff(u64, u64),
+}
+
+impl RetryPolicy {
+    /// Three attempts, one second apart.
+    pub fn default() -> RetryPolicy {
+        RetryPolicy { attempts: 3, backoff: FixedBackoff(1) }
+    }
 }
 
-#[deriving(Clone)]
+#[deriving(Clone, PartialEq)]
 pub enum Condition {
     Exists(Path),
     IsDirectory(Path),
     IsFile(Path),
     CmdCond(Command),
+    /// Whether the variable is set, even if its value is empty.
+    IsSet(String),
+    /// Whether the variable is set to the given value.
+    VarEquals(String, String),
+    /// Whether the two strings are equal, after variable references in them have been expanded.
+    StrEquals(String, String),
+    /// Whether the string, after variable references in it have been expanded, matches the
+    /// extended regular expression.
+    Matches(String, String),
+    /// Whether the command is a function, a builtin or a program on the `PATH`.
+    CommandAvailable(String),
+    /// Whether the last command exited with status zero.
+    LastSucceeded,
     And(Box<Condition>, Box<Condition>),
     Or(Box<Condition>, Box<Condition>),
     Not(Box<Condition>),
 }
 
-#[deriving(Clone)]
+#[deriving(Clone, PartialEq)]
 pub enum Command {
     Raw(String),
     Echo(String),
     Newline,
     Envset(String, String),
     Cd(Path),
-    Putfile(Path, Vec<u8>),
+    /// Write the contents to a file.
+    Putfile(Path, Vec<u8>, PutfileOptions),
     Mkdir(Path),
     Copyfile(Path, Path),
     Movefile(Path, Path),
     Removefile(Path),
+    /// Run a function defined with `Function`, with the given arguments.
+    Call(String, Vec<String>),
+    /// Run a pipeline of commands given by their arguments, which are escaped instead of being
+    /// interpreted by the shell.
+    Exec(Pipeline),
+}
+
+/// How `Putfile` writes a file.
+#[deriving(Clone, PartialEq, Show)]
+pub struct PutfileOptions {
+    /// Put the contents in the script compressed with gzip, which makes it smaller if the file is
+    /// text.
+    pub compress: bool,
+    /// The permissions the file gets, like `0o600` for a private key. It gets them before the
+    /// contents are written, so others can't read it in between.
+    pub mode: Option<u32>,
+    /// Add the contents to the end of the file instead of replacing it.
+    pub append: bool,
+}
+
+/// Commands connected with `|`, so each one reads the output of the one before it.
+#[deriving(Clone, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<SimpleCommand>,
+    /// Run the pipeline in the background, without waiting for it to finish.
+    pub background: bool,
+}
+
+#[deriving(Clone, PartialEq)]
+pub struct SimpleCommand {
+    /// The program to run, followed by its arguments.
+    pub argv: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[deriving(Clone, PartialEq)]
+pub enum Redirect {
+    /// `> path`
+    RedirectStdout(Path),
+    /// `>> path`
+    AppendStdout(Path),
+    /// `2> path`
+    RedirectStderr(Path),
+    /// `2>&1`
+    StderrToStdout,
+    /// `< path`
+    RedirectStdin(Path),
+}
+
+impl Pipeline {
+    pub fn new(commands: Vec<SimpleCommand>) -> Pipeline {
+        Pipeline { commands: commands, background: false }
+    }
+
+    /// Runs the pipeline in the background.
+    pub fn background(mut self) -> Pipeline {
+        self.background = true;
+        self
+    }
+}
+
+impl PutfileOptions {
+    /// Replaces the file with the uncompressed contents, and leaves the permissions to the umask.
+    pub fn default() -> PutfileOptions {
+        PutfileOptions { compress: false, mode: None, append: false }
+    }
+
+    /// Compresses the contents.
+    pub fn compress(mut self) -> PutfileOptions {
+        self.compress = true;
+        self
+    }
+
+    /// Gives the file the permissions.
+    pub fn mode(mut self, mode: u32) -> PutfileOptions {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Appends the contents to the file.
+    pub fn append(mut self) -> PutfileOptions {
+        self.append = true;
+        self
+    }
+}
+
+impl SimpleCommand {
+    pub fn new(argv: &[&str]) -> SimpleCommand {
+        SimpleCommand { argv: argv.iter().map(|arg| arg.to_string()).collect(), redirects: vec![] }
+    }
+
+    /// Adds a redirect, which is applied after the ones that were added before it.
+    pub fn redirect(mut self, redirect: Redirect) -> SimpleCommand {
+        self.redirects.push(redirect);
+        self
+    }
 }
 
 #[macro_export]
@@ -55,6 +220,15 @@ macro_rules! ast_if (
     ($cond:expr { $($body:expr;)* } else { $($elsebody:expr;)* }) => (::ast::If($cond, box ast_block!{ $($body);* }, box ast_block! { $($elsebody);* }))
 )
 
+/// Create a `Cmd` statement running a `Raw` command, formatted like `format!`.
+///
+/// Options can be given as a list of `CommandOption` variants without arguments.
+///
+/// # Example
+///
+/// ```ignore
+/// let statement = format_cmd!([EchoOption|RetryOption], "git fetch {}", remote);
+/// ```
 #[macro_export]
 macro_rules! format_cmd(
         ([$($opts:ident)|*], $($arg:tt)*) => (::ast::Cmd(::ast::Raw(format!($($arg)*)), vec![$(::ast::$opts),*]));
@@ -94,6 +268,7 @@ impl Statement {
     pub fn is_noop(&self) -> bool {
         match *self {
             Noop => true,
+            Traced(_, ref body) => body.is_noop(),
             _ => false
         }
     }
@@ -105,3 +280,11 @@ impl ops::Not<Condition> for Condition {
         Not(box clone)
     }
 }
+
+// These come after the macros, so they can use them.
+#[cfg(test)]
+pub mod fixtures;
+pub mod fold;
+pub mod json;
+pub mod normalize;
+pub mod visit;
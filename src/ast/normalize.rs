@@ -0,0 +1,225 @@
+//! Simplifies an AST without changing what the script it renders to does.
+
+use ast;
+use ast::{Statement, Condition};
+use ast::fold::{Folder, noop_fold_statement};
+use ast::visit::{Visitor, walk_condition};
+
+/// Flattens nested blocks, drops statements that don't do anything, collapses `If`s whose branch
+/// is known in advance and removes exports that are overwritten by the next one.
+pub fn normalize(statement: Statement) -> Statement {
+    Normalizer.fold_statement(statement)
+}
+
+struct Normalizer;
+
+impl Folder for Normalizer {
+    fn fold_statement(&mut self, statement: Statement) -> Statement {
+        // The children are normalized first, so nested blocks are already flat.
+        match noop_fold_statement(self, statement) {
+            ast::Statements(box stmts) => block(stmts),
+            ast::Fold(_, box ast::Noop) | ast::Traced(_, box ast::Noop) => ast::Noop,
+            ast::For(_, ref values, _) if values.is_empty() => ast::Noop,
+            ast::If(condition, box body, box elsebody) => simplify_if(condition, body, elsebody),
+            statement => statement,
+        }
+    }
+}
+
+fn block(stmts: Vec<Statement>) -> Statement {
+    let mut flat = Vec::with_capacity(stmts.len());
+    for stmt in stmts.into_iter() {
+        match stmt {
+            ast::Statements(box inner) => flat.extend(inner.into_iter()),
+            ast::Noop => {},
+            stmt => flat.push(stmt),
+        }
+    }
+
+    let mut stmts = drop_overwritten_exports(flat);
+    match stmts.len() {
+        0 => ast::Noop,
+        1 => stmts.pop().unwrap(),
+        _ => ast::Statements(box stmts),
+    }
+}
+
+/// Removes exports that don't print anything when the same variable is exported again before any
+/// other statement could read it.
+fn drop_overwritten_exports(stmts: Vec<Statement>) -> Vec<Statement> {
+    let mut result: Vec<Statement> = Vec::with_capacity(stmts.len());
+    // Where the exports that come right before the current statement start in `result`.
+    let mut exports_start = 0;
+
+    for stmt in stmts.into_iter() {
+        match stmt {
+            ast::Cmd(ast::Envset(ref name, _), _) => {
+                let overwritten = result.slice_from(exports_start).iter().position(|previous| is_silent_export_of(previous, name.as_slice()));
+                match overwritten {
+                    Some(i) => { result.remove(exports_start + i); },
+                    None => {},
+                }
+            },
+            _ => exports_start = result.len() + 1,
+        }
+        result.push(stmt);
+    }
+
+    result
+}
+
+fn is_silent_export_of(statement: &Statement, var: &str) -> bool {
+    match *statement {
+        ast::Cmd(ast::Envset(ref name, _), ref options) => name.as_slice() == var && options.is_empty(),
+        _ => false,
+    }
+}
+
+fn simplify_if(condition: Condition, body: Statement, elsebody: Statement) -> Statement {
+    match constant_value(&condition) {
+        Some(true) => return body,
+        Some(false) => return elsebody,
+        None => {},
+    }
+
+    if body == elsebody && !has_side_effects(&condition) {
+        return body;
+    }
+
+    match (body, elsebody) {
+        (ast::Noop, ast::Noop) => ast::If(condition, box ast::Noop, box ast::Noop),
+        // Bash doesn't allow an empty `then`, so the condition is negated instead.
+        (ast::Noop, elsebody) => ast::If(ast::Not(box condition), box elsebody, box ast::Noop),
+        (body, elsebody) => ast::If(condition, box body, box elsebody),
+    }
+}
+
+/// The value of conditions that always succeed or always fail.
+fn constant_value(condition: &Condition) -> Option<bool> {
+    match *condition {
+        ast::CmdCond(ast::Raw(ref cmd)) if cmd.as_slice() == "true" || cmd.as_slice() == ":" => Some(true),
+        ast::CmdCond(ast::Raw(ref cmd)) if cmd.as_slice() == "false" => Some(false),
+        ast::Not(ref cond) => constant_value(&**cond).map(|value| !value),
+        _ => None,
+    }
+}
+
+/// Whether evaluating the condition runs a command or sets a variable, so it can't be left out.
+fn has_side_effects(condition: &Condition) -> bool {
+    let mut finder = SideEffectFinder { found: false };
+    finder.visit_condition(condition);
+    finder.found
+}
+
+struct SideEffectFinder {
+    found: bool,
+}
+
+impl Visitor for SideEffectFinder {
+    fn visit_condition(&mut self, condition: &Condition) {
+        match *condition {
+            ast::CmdCond(_) | ast::Matches(_, _) => self.found = true,
+            _ => {},
+        }
+        walk_condition(self, condition);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize;
+    use ast;
+    use bash::ToBash;
+    use test_ast_runner::TestAstRunner;
+
+    fn assert_normalizes_to(expected: &str, statement: ast::Statement) {
+        let normalized = normalize(statement.clone());
+        assert_eq!(expected, normalized.to_bash().unwrap().as_slice());
+        assert_equivalent(&statement, &normalized);
+    }
+
+    /// Checks that both statements run the same commands and leave the same variables behind.
+    fn assert_equivalent(original: &ast::Statement, normalized: &ast::Statement) {
+        let mut original_runner = TestAstRunner::new();
+        original_runner.run(original);
+        let mut normalized_runner = TestAstRunner::new();
+        normalized_runner.run(normalized);
+
+        assert_eq!(original_runner.commands, normalized_runner.commands);
+        assert_eq!(original_runner.environment_vars, normalized_runner.environment_vars);
+        assert_eq!(original_runner.shell_vars, normalized_runner.shell_vars);
+    }
+
+    #[test]
+    fn test_flattens_blocks() {
+        let statement = ast_block! {
+            ast_block! {
+                format_cmd!("one");
+                ast::Noop;
+                ast_block! { format_cmd!("two"); };
+            };
+            ast_block! {};
+            format_cmd!("three");
+        };
+
+        let normalized = normalize(statement.clone());
+
+        assert!(normalized == ast_block! { format_cmd!("one"); format_cmd!("two"); format_cmd!("three"); });
+        assert_equivalent(&statement, &normalized);
+    }
+
+    #[test]
+    fn test_drops_empty_blocks_and_folds() {
+        assert!(normalize(ast_block! { ast::Noop; ast_block! {}; }) == ast::Noop);
+        assert!(normalize(ast::Fold("install".to_string(), box ast_block! { ast::Noop; })) == ast::Noop);
+        assert!(normalize(ast::Traced("components::git".to_string(), box ast_block! { ast::Noop; })) == ast::Noop);
+        assert!(normalize(ast::For("i".to_string(), vec![], box format_cmd!("make"))) == ast::Noop);
+        assert!(normalize(ast_block! { format_cmd!("make"); }) == format_cmd!("make"));
+    }
+
+    #[test]
+    fn test_collapses_constant_if() {
+        assert_normalizes_to("travis_cmd yes", ast_if!(ast::CmdCond(ast::Raw("true".to_string())) { format_cmd!("yes"); } else { format_cmd!("no"); }));
+        assert_normalizes_to("travis_cmd no", ast_if!(!ast::CmdCond(ast::Raw("true".to_string())) { format_cmd!("yes"); } else { format_cmd!("no"); }));
+        assert_normalizes_to("", ast_if!(ast::CmdCond(ast::Raw("false".to_string())) { format_cmd!("yes"); }));
+    }
+
+    #[test]
+    fn test_collapses_if_with_identical_branches() {
+        assert_normalizes_to("travis_cmd make", ast_if!(ast::IsFile(Path::new("Makefile")) { format_cmd!("make"); } else { format_cmd!("make"); }));
+        assert_normalizes_to("", ast_if!(ast::IsSet("CI".to_string()) { ast::Noop; }));
+
+        // The command in the condition still has to run.
+        let condition = ast::CmdCond(ast::Raw("./configure".to_string()));
+        let statement = ast_if!(condition.clone() { format_cmd!("make"); } else { format_cmd!("make"); });
+        assert!(normalize(statement) == ast::If(condition, box format_cmd!("make"), box format_cmd!("make")));
+    }
+
+    #[test]
+    fn test_negates_if_with_empty_body() {
+        assert_normalizes_to("if { ! [[ -f Makefile ]]; }; then\n  travis_cmd ./configure\nfi",
+                             ast_if!(ast::IsFile(Path::new("Makefile")) { ast::Noop; } else { format_cmd!("./configure"); }));
+    }
+
+    #[test]
+    fn test_drops_overwritten_exports() {
+        assert_normalizes_to("travis_cmd 'export B=1'\ntravis_cmd 'export A=2' --echo\ntravis_cmd 'export A=3' --echo", ast_block! {
+            ast_set!(A = "1".to_string());
+            ast_set!(A = "2".to_string());
+            ast_set!(B = "1".to_string());
+            ast_block! {
+                cmd!([EchoOption], ast::Envset("A".to_string(), "2".to_string()));
+            };
+            cmd!([EchoOption], ast::Envset("A".to_string(), "3".to_string()));
+        });
+    }
+
+    #[test]
+    fn test_keeps_exports_that_are_read() {
+        assert_normalizes_to("travis_cmd 'export A=1'\ntravis_cmd 'echo $A'\ntravis_cmd 'export A=2'", ast_block! {
+            ast_set!(A = "1".to_string());
+            format_cmd!("echo $A");
+            ast_set!(A = "2".to_string());
+        });
+    }
+}
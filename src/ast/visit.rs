@@ -0,0 +1,127 @@
+//! Walks an AST without changing it.
+//!
+//! Implement the `visit_*` methods for the nodes you are interested in, and call the matching
+//! `walk_*` function from them to keep going into the children of the node. The default methods
+//! visit every node.
+
+use ast;
+use ast::{Statement, Command, CommandOption, Condition};
+
+pub trait Visitor {
+    fn visit_statement(&mut self, statement: &Statement) {
+        walk_statement(self, statement)
+    }
+
+    fn visit_command(&mut self, command: &Command) {
+        walk_command(self, command)
+    }
+
+    fn visit_option(&mut self, _option: &CommandOption) {}
+
+    fn visit_condition(&mut self, condition: &Condition) {
+        walk_condition(self, condition)
+    }
+}
+
+pub fn walk_statement<V: Visitor>(visitor: &mut V, statement: &Statement) {
+    match *statement {
+        ast::Statements(ref stmts) => {
+            for stmt in stmts.iter() {
+                visitor.visit_statement(stmt);
+            }
+        },
+        ast::Fold(_, ref body) => visitor.visit_statement(&**body),
+        ast::Cmd(ref command, ref options) => {
+            visitor.visit_command(command);
+            for option in options.iter() {
+                visitor.visit_option(option);
+            }
+        },
+        ast::If(ref condition, ref body, ref elsebody) => {
+            visitor.visit_condition(condition);
+            visitor.visit_statement(&**body);
+            visitor.visit_statement(&**elsebody);
+        },
+        ast::For(_, _, ref body) => visitor.visit_statement(&**body),
+        ast::While(ref condition, ref body) => {
+            visitor.visit_condition(condition);
+            visitor.visit_statement(&**body);
+        },
+        ast::Function(_, _, ref body) | ast::Traced(_, ref body) => visitor.visit_statement(&**body),
+        ast::Assign(_, _) | ast::Local(_, _) | ast::Stage(_) | ast::Noop => {},
+    }
+}
+
+/// Commands don't contain other nodes, this is here so visitors can call it like the other walks.
+pub fn walk_command<V: Visitor>(_visitor: &mut V, _command: &Command) {}
+
+pub fn walk_condition<V: Visitor>(visitor: &mut V, condition: &Condition) {
+    match *condition {
+        ast::CmdCond(ref command) => visitor.visit_command(command),
+        ast::And(ref cond1, ref cond2) | ast::Or(ref cond1, ref cond2) => {
+            visitor.visit_condition(&**cond1);
+            visitor.visit_condition(&**cond2);
+        },
+        ast::Not(ref cond) => visitor.visit_condition(&**cond),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Visitor, walk_statement};
+    use ast;
+    use bash::ToBash;
+
+    struct CommandCollector {
+        commands: Vec<String>,
+    }
+
+    impl Visitor for CommandCollector {
+        fn visit_command(&mut self, command: &ast::Command) {
+            self.commands.push(command.to_bash().unwrap());
+        }
+    }
+
+    struct FoldNames {
+        names: Vec<String>,
+    }
+
+    impl Visitor for FoldNames {
+        fn visit_statement(&mut self, statement: &ast::Statement) {
+            match *statement {
+                ast::Fold(ref name, _) => self.names.push(name.clone()),
+                _ => {},
+            }
+            walk_statement(self, statement);
+        }
+    }
+
+    fn script() -> ast::Statement {
+        ast_block! {
+            ast::Fold("install".to_string(), box format_cmd!("make deps"));
+            ast_if!(ast::CmdCond(ast::Raw("test -f Makefile".to_string())) {
+                ast::Fold("script".to_string(), box ast::For("i".to_string(), vec!["1".to_string()], box format_cmd!("make test")));
+            } else {
+                ast::While(!ast::CmdCond(ast::Raw("ready".to_string())), box format_cmd!("sleep 1"));
+            });
+        }
+    }
+
+    #[test]
+    fn test_visits_every_command() {
+        let mut collector = CommandCollector { commands: vec![] };
+        collector.visit_statement(&script());
+
+        let expected: Vec<String> = vec!["make deps", "test -f Makefile", "make test", "ready", "sleep 1"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(expected, collector.commands);
+    }
+
+    #[test]
+    fn test_walk_from_overridden_method() {
+        let mut folds = FoldNames { names: vec![] };
+        folds.visit_statement(&script());
+
+        assert_eq!(vec!["install".to_string(), "script".to_string()], folds.names);
+    }
+}
@@ -1,130 +1,373 @@
 use ast;
-use serialize::base64;
-use serialize::base64::ToBase64;
+use putfile;
+use quote::{QuoteError, quote};
+use std::fmt;
 
+/// Why part of the AST couldn't be rendered.
+#[deriving(Clone, PartialEq)]
+pub struct RenderError {
+    /// The statements the node is in, outermost first, like `fold install` or the source of a
+    /// traced statement.
+    pub context: Vec<String>,
+    pub message: String,
+}
+
+pub type RenderResult<T> = Result<T, RenderError>;
+
+impl RenderError {
+    /// Records that the node is in the given statement.
+    pub fn within(mut self, statement: String) -> RenderError {
+        self.context.insert(0, statement);
+        self
+    }
+}
+
+impl fmt::Show for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for statement in self.context.iter() {
+            try!(write!(f, "{}: ", statement));
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Quotes a string so bash reads it back as one word, for building `ast::Raw` commands. A NUL byte
+/// can't be quoted, so it is left in the word, and rendering the command it ends up in fails.
 pub fn shellescape(input: &str) -> String {
-    if input.is_empty() {
-        return "''".to_string();
+    match quote(input.as_bytes()) {
+        Ok(quoted) => quoted,
+        Err(_) => format!("'{}'", input.replace("'", "'\\''")),
     }
+}
 
-    let mut output = String::with_capacity(input.len());
+fn escape(input: &str) -> RenderResult<String> {
+    escape_bytes(input.as_bytes())
+}
 
+/// Quotes a path, which doesn't have to be UTF-8.
+pub fn escape_path(path: &Path) -> RenderResult<String> {
+    escape_bytes(path.as_vec())
+}
+
+fn escape_bytes(input: &[u8]) -> RenderResult<String> {
+    quote(input).map_err(|e| quote_error(input, e))
+}
+
+/// The error for text that can't be in the script, which shows the text with its special
+/// characters escaped.
+pub fn quote_error(input: &[u8], error: QuoteError) -> RenderError {
+    let word = String::from_utf8_lossy(input).as_slice().escape_default();
+    RenderError { context: vec![], message: format!("can't render \"{}\": {}", word, error) }
+}
+
+/// Checks text that goes into the script as it is. It isn't quoted, but it still can't have a NUL
+/// byte in it.
+pub fn verbatim(input: &str) -> RenderResult<String> {
+    match input.as_bytes().iter().position(|&byte| byte == 0) {
+        Some(offset) => Err(quote_error(input.as_bytes(), QuoteError { offset: offset })),
+        None => Ok(input.to_string()),
+    }
+}
+
+/// Quotes a string with double quotes, so variable references in it are still expanded.
+pub fn double_quote(input: &str) -> RenderResult<String> {
+    try!(verbatim(input));
+    let mut output = String::with_capacity(input.len() + 2);
+    output.push('"');
     for ch in input.chars() {
-        if ch >= 'A' && ch <= 'Z' {
-            output.push(ch);
-        } else if ch >= 'a' && ch <= 'z' {
-            output.push(ch);
-        } else if ch >= '0' && ch <= '9' {
-            output.push(ch);
-        } else if ['_', '-', '.', ',', ':', '/', '@'].contains(&ch) {
-            output.push(ch);
-        } else if ch == '\n' {
-            output.push_str("'\n'");
-        } else {
+        if ['"', '\\', '`'].contains(&ch) {
             output.push('\\');
-            output.push(ch);
+        }
+        output.push(ch);
+    }
+    output.push('"');
+    Ok(output)
+}
+
+/// Indents every line, except the lines of heredocs and the lines that continue a quoted word,
+/// which are part of what they contain. `quote_posix` keeps newlines in single quotes.
+pub fn indent(input: &str) -> String {
+    let mut lines = vec![];
+    let mut heredoc_end = None;
+    let mut quote = None;
+
+    for line in input.split('\n') {
+        match heredoc_end {
+            Some(end) => {
+                lines.push(line.to_string());
+                if line == end {
+                    heredoc_end = None;
+                }
+            },
+            None => {
+                if quote.is_none() {
+                    lines.push(format!("  {}", line));
+                } else {
+                    lines.push(line.to_string());
+                }
+                quote = open_quote(line, quote);
+                if quote.is_none() {
+                    heredoc_end = putfile::heredoc_end(line);
+                }
+            },
         }
     }
 
-    output
+    lines.connect("\n")
 }
 
-fn indent(input: &str) -> String {
-    let mut result = String::new();
-    let mut iter = input.split('\n').map(|s| format!("  {}", s) );
-    let mut first = true;
+/// Returns the quote that is still open at the end of the line, given the one that was open at its
+/// start: `'`, `"`, or `$` for ANSI-C quoting.
+pub fn open_quote(line: &str, mut quote: Option<char>) -> Option<char> {
+    let mut escaped = false;
+    let mut previous = ' ';
 
-    for s in iter {
-        if first {
-            first = false;
-        } else {
-            result.push('\n');
+    for ch in line.chars() {
+        if escaped {
+            escaped = false;
+            previous = ' ';
+            continue;
         }
-        result.push_str(s.as_slice());
+
+        match quote {
+            Some('\'') if ch == '\'' => quote = None,
+            Some('\'') => {},
+            Some(_) if ch == '\\' => escaped = true,
+            Some('"') if ch == '"' => quote = None,
+            Some('$') if ch == '\'' => quote = None,
+            Some(_) => {},
+            None if ch == '\\' => escaped = true,
+            None if ch == '\'' && previous == '$' => quote = Some('$'),
+            None if ch == '\'' || ch == '"' => quote = Some(ch),
+            None => {},
+        }
+        previous = ch;
+    }
+
+    quote
+}
+
+/// Renders each of the items, stopping at the first one that can't be rendered.
+pub fn render_each<'a, T, I: Iterator<&'a T>>(items: I, render: |&T| -> RenderResult<String>) -> RenderResult<Vec<String>> {
+    let mut result = vec![];
+    for item in items {
+        result.push(try!(render(item)));
+    }
+    Ok(result)
+}
+
+/// The arguments that tell `travis_cmd` how to run a command, quoted with `escape`.
+pub fn command_options(options: &[ast::CommandOption], escape: fn(&str) -> RenderResult<String>) -> RenderResult<String> {
+    let mut options_str = String::new();
+    for option in options.iter() {
+        match *option {
+            ast::EchoOption => options_str.push_str(" --echo"),
+            ast::AssertOption => options_str.push_str(" --assert"),
+            ast::DisplayOption(ref display) => options_str.push_str(format!(" --display={}", try!(escape(display.as_slice()))).as_slice()),
+            ast::ResultOption => options_str.push_str(" --result"),
+            ast::RetryOption => options_str.push_str(" --retry"),
+            ast::TimingOption => options_str.push_str(" --timing"),
+            ast::RetryPolicyOption(ref policy) => options_str.push_str(retry_args(policy).as_slice()),
+        }
+    }
+    Ok(options_str)
+}
+
+/// The `travis_cmd` arguments that make it retry a command with the given policy.
+fn retry_args(policy: &ast::RetryPolicy) -> String {
+    match policy.backoff {
+        ast::FixedBackoff(delay) => format!(" --retry --retry-attempts={} --retry-backoff=fixed --retry-delay={}", policy.attempts, delay),
+        ast::ExponentialBackoff(delay, max_delay) => format!(" --retry --retry-attempts={} --retry-backoff=exponential --retry-delay={} --retry-max-delay={}", policy.attempts, delay, max_delay),
+    }
+}
+
+/// What the shells the AST can be rendered as don't agree on. Everything else is rendered by
+/// `render`, so statements end up on the same lines in every shell.
+pub struct Dialect {
+    /// Quotes a word.
+    pub escape: fn(&str) -> RenderResult<String>,
+    /// Quotes a path, which doesn't have to be UTF-8.
+    pub escape_path: fn(&Path) -> RenderResult<String>,
+    /// Renders a condition.
+    pub condition: fn(&ast::Condition) -> RenderResult<String>,
+    /// The command that decodes base64 from stdin, for `ast::Putfile`.
+    pub decode_base64: &'static str,
+}
+
+static BASH: Dialect = Dialect {
+    escape: escape,
+    escape_path: escape_path,
+    condition: condition,
+    decode_base64: "base64 --decode",
+};
+
+fn condition(condition: &ast::Condition) -> RenderResult<String> {
+    condition.to_bash()
+}
+
+/// Indents the body of a loop or function. The shells don't allow empty bodies, so those become
+/// `:`.
+fn block(body: &ast::Statement, dialect: &Dialect) -> RenderResult<String> {
+    let rendered = try!(render(body, dialect));
+    if rendered.is_empty() {
+        Ok(indent(":"))
+    } else {
+        Ok(indent(rendered.as_slice()))
     }
+}
 
-    result
+/// Renders a statement for the shell of the dialect.
+pub fn render(statement: &ast::Statement, dialect: &Dialect) -> RenderResult<String> {
+    let escape = dialect.escape;
+    Ok(match *statement {
+        ast::Statements(ref stmts) => {
+            let lines: Vec<String> = try!(render_each(stmts.iter().filter(|s| !s.is_noop()), |stmt| render(stmt, dialect)));
+            lines.connect("\n")
+        },
+        ast::Fold(ref fold_name, ref stmt) => {
+            let body = try!(render(&**stmt, dialect).map_err(|e| e.within(format!("fold {}", fold_name))));
+            format!("travis_fold start {0}\n{1}\ntravis_fold end {0}", fold_name, body)
+        },
+        // The contents of the file are passed to `travis_cmd` on stdin, so they aren't part of the
+        // command it prints.
+        ast::Cmd(ast::Putfile(ref path, ref contents, ref putfile_options), ref options) => {
+            try!(putfile::check_options(options.as_slice()));
+            let options = try!(command_options(options.as_slice(), escape));
+            let commands = try!(putfile::commands(path, contents.as_slice(), putfile_options, dialect.decode_base64, dialect.escape_path));
+            let lines = try!(render_each(commands.iter(), |&(ref command, ref heredoc)| Ok(format!("travis_cmd {}{} {}", try!(escape(command.as_slice())), options, heredoc))));
+            lines.connect("\n")
+        },
+        ast::Cmd(ref command, ref options) => format!("travis_cmd {}{}", try!(escape(try!(render_command(command, dialect)).as_slice())), try!(command_options(options.as_slice(), escape))),
+        ast::If(ref condition, ref body, ref elsebody) => {
+            let condition = try!((dialect.condition)(condition));
+            let body = try!(block(&**body, dialect));
+            match **elsebody {
+                ast::Noop => format!("if {}; then\n{}\nfi", condition, body),
+                ast::If(_, _, _) => format!("if {}; then\n{}\nel{}", condition, body, try!(render(&**elsebody, dialect))),
+                _ => format!("if {}; then\n{}\nelse\n{}\nfi", condition, body, try!(block(&**elsebody, dialect)))
+            }
+        },
+        ast::For(ref var, ref values, ref body) => {
+            let values: Vec<String> = try!(render_each(values.iter(), |value| escape(value.as_slice())));
+            format!("for {} in {}; do\n{}\ndone", var, values.connect(" "), try!(block(&**body, dialect)))
+        },
+        ast::While(ref condition, ref body) => format!("while {}; do\n{}\ndone", try!((dialect.condition)(condition)), try!(block(&**body, dialect))),
+        // `local` isn't POSIX, but dash and busybox support it, so function parameters and `Local`s
+        // are local in every shell.
+        ast::Function(ref name, ref params, ref body) => {
+            let mut lines: Vec<String> = params.iter().enumerate().map(|(i, param)| format!("  local {}=\"${{{}}}\"", param, i + 1)).collect();
+            lines.push(try!(block(&**body, dialect).map_err(|e| e.within(format!("function {}", name)))));
+            format!("{}() {{\n{}\n}}", name, lines.connect("\n"))
+        },
+        ast::Assign(ref var, ref value) => format!("{}={}", var, try!(escape(value.as_slice()))),
+        ast::Local(ref var, ref value) => format!("local {}={}", var, try!(escape(value.as_slice()))),
+        ast::Traced(ref source, ref body) => try!(render(&**body, dialect).map_err(|e| e.within(source.clone()))),
+        ast::Stage(ref name) => format!("export TRAVIS_STAGE={}", try!(escape(name.as_slice()))),
+        ast::Noop => "".to_string()
+    })
 }
 
+/// Renders a command for the shell of the dialect.
+pub fn render_command(command: &ast::Command, dialect: &Dialect) -> RenderResult<String> {
+    let escape = dialect.escape;
+    let escape_path = dialect.escape_path;
+    Ok(match *command {
+        ast::Raw(ref cmd) => try!(verbatim(cmd.as_slice())),
+        ast::Echo(ref string) => format!("echo {}", try!(verbatim(string.as_slice()))),
+        ast::Newline => "echo".to_string(),
+        ast::Envset(ref var, ref value) => format!("export {}={}", var, try!(escape(value.as_slice()))),
+        ast::Cd(ref path) => format!("cd {}", try!(escape_path(path))),
+        ast::Putfile(ref path, ref contents, ref options) => {
+            let commands = try!(putfile::commands(path, contents.as_slice(), options, dialect.decode_base64, escape_path));
+            let lines: Vec<String> = commands.into_iter().map(|(command, heredoc)| format!("{} {}", command, heredoc)).collect();
+            lines.connect("\n")
+        },
+        ast::Mkdir(ref path) => format!("mkdir -p {}", try!(escape_path(path))),
+        ast::Copyfile(ref from_path, ref to_path) => format!("cp -r {} {}", try!(escape_path(from_path)), try!(escape_path(to_path))),
+        ast::Movefile(ref from_path, ref to_path) => format!("mv {} {}", try!(escape_path(from_path)), try!(escape_path(to_path))),
+        ast::Removefile(ref path) => format!("rm -rf {}", try!(escape_path(path))),
+        ast::Call(ref name, ref args) => {
+            let mut words = vec![name.clone()];
+            words.push_all(try!(render_each(args.iter(), |arg| escape(arg.as_slice()))).as_slice());
+            words.connect(" ")
+        },
+        ast::Exec(ref pipeline) => try!(render_pipeline(pipeline, dialect)),
+    })
+}
+
+fn render_pipeline(pipeline: &ast::Pipeline, dialect: &Dialect) -> RenderResult<String> {
+    let commands: Vec<String> = try!(render_each(pipeline.commands.iter(), |command| render_simple_command(command, dialect)));
+    let mut result = commands.connect(" | ");
+    if pipeline.background {
+        result.push_str(" &");
+    }
+    Ok(result)
+}
+
+fn render_simple_command(command: &ast::SimpleCommand, dialect: &Dialect) -> RenderResult<String> {
+    let escape = dialect.escape;
+    let escape_path = dialect.escape_path;
+    let mut words: Vec<String> = try!(render_each(command.argv.iter(), |arg| escape(arg.as_slice())));
+    for redirect in command.redirects.iter() {
+        words.push(match *redirect {
+            ast::RedirectStdout(ref path) => format!("> {}", try!(escape_path(path))),
+            ast::AppendStdout(ref path) => format!(">> {}", try!(escape_path(path))),
+            ast::RedirectStderr(ref path) => format!("2> {}", try!(escape_path(path))),
+            ast::StderrToStdout => "2>&1".to_string(),
+            ast::RedirectStdin(ref path) => format!("< {}", try!(escape_path(path))),
+        });
+    }
+    Ok(words.connect(" "))
+}
+
+/// Renders the AST as bash. Rendering fails if a word in it can't be quoted, which can only happen
+/// if it has a NUL byte.
 pub trait ToBash {
-    fn to_bash(&self) -> String;
+    fn to_bash(&self) -> RenderResult<String>;
 }
 
 impl ToBash for ast::Statement {
-    fn to_bash(&self) -> String {
-        match self {
-            &ast::Statements(ref stmts) => {
-                let mut result = String::new();
-                let mut iter = stmts.iter().filter(|s| !s.is_noop()).map(|stmt| stmt.to_bash());
-                let mut first = true;
-
-                for s in iter {
-                    if first {
-                        first = false;
-                    } else {
-                        result.push_str("\n");
-                    }
-                    result.push_str(s.as_slice());
-                }
-                result
-            },
-            &ast::Fold(ref fold_name, ref stmt) => format!("travis_fold start {0}\n{1}\ntravis_fold end {0}", fold_name, stmt.to_bash()),
-            &ast::Cmd(ref command, ref options) => {
-                let mut options_str = String::new();
-                for option in options.iter() {
-                    match *option {
-                        ast::EchoOption => options_str.push_str(" --echo"),
-                        ast::AssertOption => options_str.push_str(" --assert"),
-                        ast::DisplayOption(ref display) => options_str.push_str(format!(" --display={}", shellescape(display.as_slice())).as_slice()),
-                    }
-                }
-
-                format!("travis_cmd {}{}", shellescape(command.to_bash().as_slice()), options_str)
-            },
-            &ast::If(ref condition, ref body, ref elsebody) => {
-                match **elsebody {
-                    ast::Noop => format!("if {}; then\n{}\nfi", condition.to_bash(), indent(body.to_bash().as_slice())),
-                    ast::If(_, _, _) => format!("if {}; then\n{}\nel{}", condition.to_bash(), indent(body.to_bash().as_slice()), indent(elsebody.to_bash().as_slice())),
-                    _ => format!("if {}; then\n{}\nelse\n{}\nfi", condition.to_bash(), indent(body.to_bash().as_slice()), indent(elsebody.to_bash().as_slice()))
-                }
-            },
-            &ast::Noop => "".to_string()
-        }
+    fn to_bash(&self) -> RenderResult<String> {
+        render(self, &BASH)
     }
 }
 
 impl ToBash for ast::Command {
-    fn to_bash(&self) -> String {
-        match *self {
-            ast::Raw(ref cond) => cond.clone(),
-            ast::Echo(ref string) => format!("echo {}", string),
-            ast::Newline => "echo".to_string(),
-            ast::Envset(ref var, ref value) => format!("export {}={}", var, shellescape(value.as_slice())),
-            ast::Cd(ref path) => format!("cd {}", shellescape(path.as_str().unwrap())),
-            ast::Putfile(ref path, ref contents) => {
-                let path_str = path.as_str().unwrap();
-                let base64_body = contents.as_slice().to_base64(base64::STANDARD);
-                format!("base64 --decode > {} <<<{}", shellescape(path_str), shellescape(base64_body.as_slice()))
-            },
-            ast::Mkdir(ref path) => format!("mkdir -p {}", shellescape(path.as_str().unwrap())),
-            ast::Copyfile(ref from_path, ref to_path) => format!("cp -r {} {}", shellescape(from_path.as_str().unwrap()), shellescape(to_path.as_str().unwrap())),
-            ast::Movefile(ref from_path, ref to_path) => format!("mv {} {}", shellescape(from_path.as_str().unwrap()), shellescape(to_path.as_str().unwrap())),
-            ast::Removefile(ref path) => format!("rm -rf {}", shellescape(path.as_str().unwrap())),
-        }
+    fn to_bash(&self) -> RenderResult<String> {
+        render_command(self, &BASH)
+    }
+}
+
+impl ToBash for ast::Pipeline {
+    fn to_bash(&self) -> RenderResult<String> {
+        render_pipeline(self, &BASH)
+    }
+}
+
+impl ToBash for ast::SimpleCommand {
+    fn to_bash(&self) -> RenderResult<String> {
+        render_simple_command(self, &BASH)
     }
 }
 
 impl ToBash for ast::Condition {
-    fn to_bash(&self) -> String {
-        match *self {
-            ast::Exists(ref path) => format!("[[ -e {} ]]", shellescape(path.as_str().unwrap())),
-            ast::IsDirectory(ref path) => format!("[[ -d {} ]]", shellescape(path.as_str().unwrap())),
-            ast::IsFile(ref path) => format!("[[ -f {} ]]", shellescape(path.as_str().unwrap())),
-            ast::CmdCond(ref command) => command.to_bash(),
-            ast::And(ref cond1, ref cond2) => format!("{{ {} && {}; }}", cond1.to_bash(), cond2.to_bash()),
-            ast::Or(ref cond1, ref cond2) => format!("{{ {} || {}; }}", cond1.to_bash(), cond2.to_bash()),
-            ast::Not(ref condition) => format!("{{ ! {}; }}", condition.to_bash()),
-        }
+    fn to_bash(&self) -> RenderResult<String> {
+        Ok(match *self {
+            ast::Exists(ref path) => format!("[[ -e {} ]]", try!(escape_path(path))),
+            ast::IsDirectory(ref path) => format!("[[ -d {} ]]", try!(escape_path(path))),
+            ast::IsFile(ref path) => format!("[[ -f {} ]]", try!(escape_path(path))),
+            ast::CmdCond(ref command) => try!(command.to_bash()),
+            ast::IsSet(ref var) => format!("[[ -n ${{{}+x}} ]]", var),
+            ast::VarEquals(ref var, ref value) => format!("[[ ${} = {} ]]", var, try!(escape(value.as_slice()))),
+            ast::StrEquals(ref left, ref right) => format!("[[ {} = {} ]]", try!(double_quote(left.as_slice())), try!(double_quote(right.as_slice()))),
+            // The regex is kept in a variable, because bash would match quoted parts of it literally.
+            ast::Matches(ref string, ref regex) => format!("{{ travis_re={}; [[ {} =~ $travis_re ]]; }}", try!(escape(regex.as_slice())), try!(double_quote(string.as_slice()))),
+            ast::CommandAvailable(ref command) => format!("command -v {} &> /dev/null", try!(escape(command.as_slice()))),
+            ast::LastSucceeded => "[[ $? = 0 ]]".to_string(),
+            ast::And(ref cond1, ref cond2) => format!("{{ {} && {}; }}", try!(cond1.to_bash()), try!(cond2.to_bash())),
+            ast::Or(ref cond1, ref cond2) => format!("{{ {} || {}; }}", try!(cond1.to_bash()), try!(cond2.to_bash())),
+            ast::Not(ref condition) => format!("{{ ! {}; }}", try!(condition.to_bash())),
+        })
     }
 }
 
@@ -132,6 +375,7 @@ impl ToBash for ast::Condition {
 mod test {
     use super::ToBash;
     use ast;
+    use putfile::CHUNK_SIZE;
     use std::path::Path;
 
     fn cmd() -> ast::Statement {
@@ -140,36 +384,124 @@ mod test {
 
     #[test]
     fn test_statement_to_bash() {
-        assert_eq!("travis_cmd hello\\ world", cmd().to_bash().as_slice());
-        assert_eq!("travis_cmd hello\\ world --echo --display=this\\ is\\ output --assert", ast::Cmd(ast::Raw("hello world".to_string()), vec![ast::EchoOption, ast::DisplayOption("this is output".to_string()), ast::AssertOption]).to_bash().as_slice());
-        assert_eq!("travis_fold start hello\ntravis_cmd hello\\ world\ntravis_fold end hello", ast::Fold("hello".to_string(), box cmd()).to_bash().as_slice());
-        assert_eq!("", ast::Noop.to_bash().as_slice());
-        assert_eq!("if true; then\n  travis_cmd hello\\ world\nfi", ast::If(ast::CmdCond(ast::Raw("true".to_string())), box cmd(), box ast::Noop).to_bash().as_slice());
-        assert_eq!("travis_cmd hello\\ world\ntravis_cmd hello\\ world", ast::Statements(box vec![cmd(), cmd()]).to_bash().as_slice());
+        assert_eq!("travis_cmd 'hello world'", cmd().to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world' --echo --display='this is output' --assert", ast::Cmd(ast::Raw("hello world".to_string()), vec![ast::EchoOption, ast::DisplayOption("this is output".to_string()), ast::AssertOption]).to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world' --retry --timing", ast::Cmd(ast::Raw("hello world".to_string()), vec![ast::RetryOption, ast::TimingOption]).to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world' --retry --timing", format_cmd!([RetryOption|TimingOption], "hello {}", "world").to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world' --retry --retry-attempts=5 --retry-backoff=fixed --retry-delay=10", ast::Cmd(ast::Raw("hello world".to_string()), vec![ast::RetryPolicyOption(ast::RetryPolicy { attempts: 5, backoff: ast::FixedBackoff(10) })]).to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world' --retry --retry-attempts=4 --retry-backoff=exponential --retry-delay=2 --retry-max-delay=30", ast::Cmd(ast::Raw("hello world".to_string()), vec![ast::RetryPolicyOption(ast::RetryPolicy { attempts: 4, backoff: ast::ExponentialBackoff(2, 30) })]).to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world' --echo --result", ast::Cmd(ast::Raw("hello world".to_string()), vec![ast::EchoOption, ast::ResultOption]).to_bash().unwrap().as_slice());
+        assert_eq!("travis_fold start hello\ntravis_cmd 'hello world'\ntravis_fold end hello", ast::Fold("hello".to_string(), box cmd()).to_bash().unwrap().as_slice());
+        assert_eq!("", ast::Noop.to_bash().unwrap().as_slice());
+        assert_eq!("if true; then\n  travis_cmd 'hello world'\nfi", ast::If(ast::CmdCond(ast::Raw("true".to_string())), box cmd(), box ast::Noop).to_bash().unwrap().as_slice());
+        assert_eq!("if true; then\n  travis_cmd 'hello world'\nelif false; then\n  travis_cmd 'hello world'\nfi", ast::If(ast::CmdCond(ast::Raw("true".to_string())), box cmd(), box ast::If(ast::CmdCond(ast::Raw("false".to_string())), box cmd(), box ast::Noop)).to_bash().unwrap().as_slice());
+        assert_eq!("if true; then\n  :\nelse\n  travis_cmd 'hello world'\nfi", ast::If(ast::CmdCond(ast::Raw("true".to_string())), box ast::Noop, box cmd()).to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world'\ntravis_cmd 'hello world'", ast::Statements(box vec![cmd(), cmd()]).to_bash().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_loops_to_bash() {
+        assert_eq!("for toolchain in stable 'nightly 2014'; do\n  travis_cmd 'hello world'\ndone", ast::For("toolchain".to_string(), vec!["stable".to_string(), "nightly 2014".to_string()], box cmd()).to_bash().unwrap().as_slice());
+        assert_eq!("while [[ -e lock ]]; do\n  travis_cmd 'hello world'\ndone", ast::While(ast::Exists(Path::new("lock")), box cmd()).to_bash().unwrap().as_slice());
+        assert_eq!("while [[ -e lock ]]; do\n  :\ndone", ast::While(ast::Exists(Path::new("lock")), box ast::Noop).to_bash().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_functions_to_bash() {
+        assert_eq!("greet() {\n  local name=\"${1}\"\n  local greeting=\"${2}\"\n  travis_cmd 'hello world'\n}", ast::Function("greet".to_string(), vec!["name".to_string(), "greeting".to_string()], box cmd()).to_bash().unwrap().as_slice());
+        assert_eq!("noop() {\n  :\n}", ast::Function("noop".to_string(), vec![], box ast::Noop).to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'greet '\\''a b'\\''' --echo", ast::Cmd(ast::Call("greet".to_string(), vec!["a b".to_string()]), vec![ast::EchoOption]).to_bash().unwrap().as_slice());
+        assert_eq!("FOO='bar baz'", ast::Assign("FOO".to_string(), "bar baz".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("local FOO='bar baz'", ast::Local("FOO".to_string(), "bar baz".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("export TRAVIS_STAGE=before_install", ast::Stage("before_install".to_string()).to_bash().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_traced_to_bash() {
+        assert_eq!("travis_cmd 'hello world'", ast::Traced("config.script[0]".to_string(), box cmd()).to_bash().unwrap().as_slice());
+        assert_eq!("travis_cmd 'hello world'", ast_block! { ast::Traced("components::services".to_string(), box ast::Noop); cmd(); }.to_bash().unwrap().as_slice());
     }
 
     #[test]
     fn test_command_to_bash() {
-        assert_eq!("foo bar", ast::Raw("foo bar".to_string()).to_bash().as_slice());
-        assert_eq!("echo foo bar", ast::Echo("foo bar".to_string()).to_bash().as_slice());
-        assert_eq!("echo", ast::Newline.to_bash().as_slice());
-        assert_eq!("export FOO=bar\\ baz", ast::Envset("FOO".to_string(), "bar baz".to_string()).to_bash().as_slice());
-        assert_eq!("cd path/to/some\\ where", ast::Cd(Path::new(b"path/to/some where")).to_bash().as_slice());
-        assert_eq!("base64 --decode > path/to/file <<<aGVsbG8gd29ybGQ\\=", ast::Putfile(Path::new("path/to/file"), b"hello world".to_vec()).to_bash().as_slice());
-        assert_eq!("mkdir -p path/to/dir", ast::Mkdir(Path::new("path/to/dir")).to_bash().as_slice());
-        assert_eq!("cp -r path/from path/to", ast::Copyfile(Path::new("path/from"), Path::new("path/to")).to_bash().as_slice());
-        assert_eq!("mv path/from path/to", ast::Movefile(Path::new("path/from"), Path::new("path/to")).to_bash().as_slice());
-        assert_eq!("rm -rf path/to/remove", ast::Removefile(Path::new("path/to/remove")).to_bash().as_slice());
+        assert_eq!("foo bar", ast::Raw("foo bar".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("echo foo bar", ast::Echo("foo bar".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("echo", ast::Newline.to_bash().unwrap().as_slice());
+        assert_eq!("export FOO='bar baz'", ast::Envset("FOO".to_string(), "bar baz".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("cd 'path/to/some where'", ast::Cd(Path::new(b"path/to/some where")).to_bash().unwrap().as_slice());
+        assert_eq!("cd $'caf\\xc3'", ast::Cd(Path::new(b"caf\xc3")).to_bash().unwrap().as_slice());
+        assert_eq!("export BRANCH='f\u00fcr-caf\u00e9'", ast::Envset("BRANCH".to_string(), "f\u00fcr-caf\u00e9".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("export MESSAGE=$'one\\ntwo'", ast::Envset("MESSAGE".to_string(), "one\ntwo".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("base64 --decode > path/to/file <<'TRAVIS_PUTFILE'\naGVsbG8gd29ybGQ=\nTRAVIS_PUTFILE", ast::Putfile(Path::new("path/to/file"), b"hello world".to_vec(), ast::PutfileOptions::default()).to_bash().unwrap().as_slice());
+        assert_eq!("mkdir -p path/to/dir", ast::Mkdir(Path::new("path/to/dir")).to_bash().unwrap().as_slice());
+        assert_eq!("cp -r path/from path/to", ast::Copyfile(Path::new("path/from"), Path::new("path/to")).to_bash().unwrap().as_slice());
+        assert_eq!("mv path/from path/to", ast::Movefile(Path::new("path/from"), Path::new("path/to")).to_bash().unwrap().as_slice());
+        assert_eq!("rm -rf path/to/remove", ast::Removefile(Path::new("path/to/remove")).to_bash().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_putfile_to_bash() {
+        let key = cmd!([EchoOption], ast::Putfile(Path::new(".ssh/id_rsa"), b"key".to_vec(), ast::PutfileOptions::default().mode(0o600)));
+        assert_eq!("travis_cmd 'touch .ssh/id_rsa && chmod 600 .ssh/id_rsa && base64 --decode > .ssh/id_rsa' --echo <<'TRAVIS_PUTFILE'\na2V5\nTRAVIS_PUTFILE", key.to_bash().unwrap().as_slice());
+        // The lines of the heredoc aren't indented, or they wouldn't end it.
+        assert_eq!("if [[ -n ${CI+x} ]]; then\n  travis_cmd 'base64 --decode >> log' <<'TRAVIS_PUTFILE'\nbGluZQo=\nTRAVIS_PUTFILE\n  travis_cmd 'cat log'\nfi",
+                   ast_if!(ast::IsSet("CI".to_string()) {
+                       cmd!(ast::Putfile(Path::new("log"), b"line\n".to_vec(), ast::PutfileOptions::default().append()));
+                       format_cmd!("cat log");
+                   }).to_bash().unwrap().as_slice());
+
+        let compressed = cmd!(ast::Putfile(Path::new("big"), Vec::from_elem(CHUNK_SIZE + 1, b'a'), ast::PutfileOptions::default().compress()));
+        let bash = compressed.to_bash().unwrap();
+        let commands: Vec<&str> = bash.as_slice().lines().filter(|line| line.starts_with("travis_cmd")).collect();
+        assert_eq!(vec!["travis_cmd 'base64 --decode | gunzip > big' <<'TRAVIS_PUTFILE'", "travis_cmd 'base64 --decode | gunzip >> big' <<'TRAVIS_PUTFILE'"], commands);
+
+        let retried = cmd!([AssertOption|RetryOption], ast::Putfile(Path::new("key"), b"key".to_vec(), ast::PutfileOptions::default()));
+        assert_eq!("can't retry writing a file, its contents are read from stdin", retried.to_bash().unwrap_err().message.as_slice());
+        let policy = ast::RetryPolicyOption(ast::RetryPolicy::default());
+        assert!(ast::Cmd(ast::Putfile(Path::new("key"), b"key".to_vec(), ast::PutfileOptions::default()), vec![policy]).to_bash().is_err());
+    }
+
+    #[test]
+    fn test_pipeline_to_bash() {
+        let grep = ast::SimpleCommand::new(&["grep", "it's", "a file"]).redirect(ast::RedirectStdin(Path::new("in put")));
+        let tee = ast::SimpleCommand::new(&["sudo", "tee", "/etc/hosts"])
+            .redirect(ast::RedirectStdout(Path::new("/dev/null")))
+            .redirect(ast::StderrToStdout);
+        assert_eq!("grep 'it'\\''s' 'a file' < 'in put' | sudo tee /etc/hosts > /dev/null 2>&1", ast::Exec(ast::Pipeline::new(vec![grep, tee])).to_bash().unwrap().as_slice());
+
+        let server = ast::SimpleCommand::new(&["./server", "$PORT"])
+            .redirect(ast::AppendStdout(Path::new("server.log")))
+            .redirect(ast::RedirectStderr(Path::new("errors.log")));
+        assert_eq!("./server '$PORT' >> server.log 2> errors.log &", ast::Exec(ast::Pipeline::new(vec![server]).background()).to_bash().unwrap().as_slice());
     }
 
     #[test]
     fn test_condition_to_bash() {
-        assert_eq!("[[ -e this/is\\ the/path ]]", ast::Exists(Path::new("this/is the/path")).to_bash().as_slice());
-        assert_eq!("[[ -d this/is\\ the/path ]]", ast::IsDirectory(Path::new("this/is the/path")).to_bash().as_slice());
-        assert_eq!("[[ -f this/is\\ the/path ]]", ast::IsFile(Path::new("this/is the/path")).to_bash().as_slice());
-        assert_eq!("hello world", ast::CmdCond(ast::Raw("hello world".to_string())).to_bash().as_slice());
-        assert_eq!("{ this && that; }", ast::And(box ast::CmdCond(ast::Raw("this".to_string())), box ast::CmdCond(ast::Raw("that".to_string()))).to_bash().as_slice());
-        assert_eq!("{ this || that; }", ast::Or(box ast::CmdCond(ast::Raw("this".to_string())), box ast::CmdCond(ast::Raw("that".to_string()))).to_bash().as_slice());
-        assert_eq!("{ ! this; }", ast::Not(box ast::CmdCond(ast::Raw("this".to_string()))).to_bash().as_slice());
+        assert_eq!("[[ -n ${FOO+x} ]]", ast::IsSet("FOO".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("[[ $FOO = 'a *b' ]]", ast::VarEquals("FOO".to_string(), "a *b".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("[[ \"$HOME/a \\\"b\\\"\" = \"\\`c\\`\" ]]", ast::StrEquals("$HOME/a \"b\"".to_string(), "`c`".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("{ travis_re='^1\\.[0-9]+$'; [[ \"$TRAVIS_RUST_VERSION\" =~ $travis_re ]]; }", ast::Matches("$TRAVIS_RUST_VERSION".to_string(), "^1\\.[0-9]+$".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("command -v cargo &> /dev/null", ast::CommandAvailable("cargo".to_string()).to_bash().unwrap().as_slice());
+        assert_eq!("[[ $? = 0 ]]", ast::LastSucceeded.to_bash().unwrap().as_slice());
+        assert_eq!("[[ -e 'this/is the/path' ]]", ast::Exists(Path::new("this/is the/path")).to_bash().unwrap().as_slice());
+        assert_eq!("[[ -d 'this/is the/path' ]]", ast::IsDirectory(Path::new("this/is the/path")).to_bash().unwrap().as_slice());
+        assert_eq!("[[ -f 'this/is the/path' ]]", ast::IsFile(Path::new("this/is the/path")).to_bash().unwrap().as_slice());
+        assert_eq!("hello world", ast::CmdCond(ast::Raw("hello world".to_string())).to_bash().unwrap().as_slice());
+        assert_eq!("{ this && that; }", ast::And(box ast::CmdCond(ast::Raw("this".to_string())), box ast::CmdCond(ast::Raw("that".to_string()))).to_bash().unwrap().as_slice());
+        assert_eq!("{ this || that; }", ast::Or(box ast::CmdCond(ast::Raw("this".to_string())), box ast::CmdCond(ast::Raw("that".to_string()))).to_bash().unwrap().as_slice());
+        assert_eq!("{ ! this; }", ast::Not(box ast::CmdCond(ast::Raw("this".to_string()))).to_bash().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_nul_bytes_cant_be_rendered() {
+        let statement = ast::Traced("config.script[0]".to_string(), box ast::Fold("script".to_string(), box ast::Cmd(ast::Raw("echo a\x00b".to_string()), vec![])));
+        let error = statement.to_bash().unwrap_err();
+        assert_eq!(vec!["config.script[0]".to_string(), "fold script".to_string()], error.context);
+        assert_eq!("config.script[0]: fold script: can't render \"echo a\\x00b\": NUL byte at offset 6", error.to_string().as_slice());
+
+        assert!(ast::Envset("FOO".to_string(), "\x00".to_string()).to_bash().is_err());
+        assert!(ast::Cmd(ast::Raw("make".to_string()), vec![ast::DisplayOption("\x00".to_string())]).to_bash().is_err());
+        assert!(ast::StrEquals("$FOO".to_string(), "a\x00".to_string()).to_bash().is_err());
+        assert_eq!(vec!["function f".to_string()], ast::Function("f".to_string(), vec![], box ast::Assign("A".to_string(), "\x00".to_string())).to_bash().unwrap_err().context);
     }
 }
@@ -0,0 +1,257 @@
+use ast;
+use bash;
+use payload::{Payload,EnvEntry,Plain,Secure,is_variable_name};
+use secure;
+
+/// A variable assignment from the `env` section of the config.
+#[deriving(Clone, PartialEq, Show)]
+pub struct EnvVar {
+    pub name: String,
+    /// The value with the quotes and escapes removed. Variable references are not expanded.
+    pub value: String,
+    /// The assignment as it was written in the config.
+    pub source: String,
+    /// Whether the variable was decrypted from a secure entry, so its value must not be shown.
+    pub secure: bool,
+}
+
+impl EnvVar {
+    /// The line shown in the build log when the variable is exported.
+    pub fn display(&self) -> String {
+        if self.secure {
+            format!("export {}=[secure]", self.name)
+        } else {
+            format!("export {}", self.source)
+        }
+    }
+}
+
+/// Exports the variables from the config. Secure variables are left out of pull request builds,
+/// since the code of a pull request could reveal them.
+pub fn export_env_ast(payload: &Payload) -> ast::Statement {
+    let env = &payload.config.env;
+    let entries: Vec<&EnvEntry> = env.global.iter().chain(env.matrix.iter())
+        .filter(|entry| !(payload.job.pull_request && entry.is_secure()))
+        .collect();
+
+    if entries.is_empty() {
+        return ast::Noop;
+    }
+
+    let mut stmts = vec![
+        cmd!(ast::Newline),
+        cmd!(ast::Echo("Setting environment variables from .travis.yml".to_string())),
+    ];
+    for entry in entries.into_iter() {
+        match *entry {
+            Plain(ref assignments) => stmts.extend(parse_assignments(assignments.as_slice()).iter().map(export_ast)),
+            Secure(ref value) => match decrypt(payload, value.as_slice()) {
+                Ok(assignments) => stmts.extend(parse_assignments(assignments.as_slice()).into_iter().map(|mut var| {
+                    var.secure = true;
+                    export_ast(&var)
+                })),
+                Err(e) => stmts.push(cmd!(ast::Echo(bash::shellescape(format!("Couldn't decrypt a secure environment variable: {}", e).as_slice())))),
+            },
+        }
+    }
+    stmts.push(cmd!(ast::Newline));
+
+    ast::Statements(box stmts)
+}
+
+/// Exports a variable and shows its display in the build log. `travis_cmd` prints the commands it
+/// runs when they fail, so the value of a secure variable is assigned outside of it and only its
+/// name is passed to `export`.
+fn export_ast(var: &EnvVar) -> ast::Statement {
+    if var.secure {
+        ast_block!(
+            cmd!(ast::Echo(bash::shellescape(format!("$ {}", var.display()).as_slice())));
+            ast::Assign(var.name.clone(), var.value.clone());
+            format_cmd!("export {}", var.name);
+        )
+    } else {
+        ast::Cmd(ast::Envset(var.name.clone(), var.value.clone()), vec![ast::EchoOption, ast::DisplayOption(var.display())])
+    }
+}
+
+fn decrypt(payload: &Payload, value: &str) -> Result<String, String> {
+    match payload.repository.private_key {
+        Some(ref private_key) => secure::decrypt(private_key.as_slice(), value),
+        None => Err("the payload doesn't include the private key of the repository".to_string()),
+    }
+}
+
+/// Parses a string of assignments like `FOO=bar BAZ="a b"`, with the quoting rules of the shell.
+///
+/// Words that aren't assignments to a valid variable name are skipped.
+pub fn parse_assignments(input: &str) -> Vec<EnvVar> {
+    let chars: Vec<(uint, char)> = input.char_indices().collect();
+    let mut vars = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        while pos < chars.len() && chars[pos].val1().is_whitespace() {
+            pos += 1;
+        }
+        if pos >= chars.len() {
+            break;
+        }
+
+        let start = pos;
+        let mut name = String::new();
+        let mut value = String::new();
+        let mut in_value = false;
+        let mut quote = None;
+
+        while pos < chars.len() {
+            let ch = chars[pos].val1();
+            pos += 1;
+
+            match quote {
+                Some('\'') if ch == '\'' => quote = None,
+                Some('"') if ch == '"' => quote = None,
+                Some('"') if ch == '\\' && pos < chars.len() && ['"', '\\', '$', '`'].contains(&chars[pos].val1()) => {
+                    value.push(chars[pos].val1());
+                    pos += 1;
+                },
+                Some(_) => value.push(ch),
+                None if ch.is_whitespace() => break,
+                None if !in_value && ch == '=' => in_value = true,
+                None if !in_value => name.push(ch),
+                None if ch == '\'' || ch == '"' => quote = Some(ch),
+                None if ch == '\\' && pos < chars.len() => {
+                    value.push(chars[pos].val1());
+                    pos += 1;
+                },
+                None => value.push(ch),
+            }
+        }
+
+        let end = if pos < chars.len() { chars[pos].val0() } else { input.len() };
+        if in_value && is_variable_name(name.as_slice()) {
+            vars.push(EnvVar {
+                name: name,
+                value: value,
+                source: input.slice(chars[start].val0(), end).trim_right().to_string(),
+                secure: false,
+            });
+        }
+    }
+
+    vars
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export_env_ast, parse_assignments, EnvVar};
+    use bash;
+    use bash::ToBash;
+    use test_ast_runner::TestAstRunner;
+    use payload::{Plain,Secure};
+    use payload::test::a_payload;
+    use secure::test::{PRIVATE_KEY, ENCRYPTED_TOKEN};
+
+    fn var(name: &str, value: &str, source: &str) -> EnvVar {
+        EnvVar { name: name.to_string(), value: value.to_string(), source: source.to_string(), secure: false }
+    }
+
+    fn assert_command_was_run(runner: &TestAstRunner, expected: &str) {
+        assert!(runner.commands.iter().any(|&(ref command, _)| command.as_slice() == expected), "expected command '{}' to be run, but wasn't: {}", expected, runner.commands);
+    }
+
+    #[test]
+    fn test_parse_assignments() {
+        assert_eq!(vec![var("FOO", "bar", "FOO=bar")], parse_assignments("FOO=bar"));
+        assert_eq!(vec![var("FOO", "bar", "FOO=bar"), var("BAZ", "a b", "BAZ=\"a b\"")], parse_assignments("FOO=bar BAZ=\"a b\""));
+        assert_eq!(vec![var("A", "it's $HOME", "A='it'\\''s $HOME'")], parse_assignments("  A='it'\\''s $HOME'  "));
+        assert_eq!(vec![var("A", "\"x\"", "A=\"\\\"x\\\"\"")], parse_assignments("A=\"\\\"x\\\"\""));
+        assert_eq!(vec![var("EMPTY", "", "EMPTY=")], parse_assignments("EMPTY="));
+    }
+
+    #[test]
+    fn test_parse_assignments_skips_invalid() {
+        assert_eq!(vec![var("B", "2", "B=2")], parse_assignments("oops 1A=1 B=2 =3"));
+    }
+
+    #[test]
+    fn test_export_env() {
+        let mut payload = a_payload();
+        payload.config.env.global = vec![Plain("FOO=bar".to_string())];
+        payload.config.env.matrix = vec![Plain("SUITE=\"unit tests\" DB=pg".to_string())];
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&export_env_ast(&payload));
+
+        assert_eq!(Some(&"bar".to_string()), runner.environment_vars.find(&"FOO".to_string()));
+        assert_eq!(Some(&"unit tests".to_string()), runner.environment_vars.find(&"SUITE".to_string()));
+        assert_eq!(Some(&"pg".to_string()), runner.environment_vars.find(&"DB".to_string()));
+    }
+
+    #[test]
+    fn test_export_env_display() {
+        let mut payload = a_payload();
+        payload.config.env.matrix = vec![Plain("SUITE=\"unit tests\"".to_string())];
+
+        let bash = export_env_ast(&payload).to_bash().unwrap();
+
+        assert!(bash.as_slice().contains("travis_cmd 'export SUITE='\\''unit tests'\\''' --echo --display='export SUITE=\"unit tests\"'\n"), "unexpected script: {}", bash);
+    }
+
+    #[test]
+    fn test_secure_env() {
+        let mut payload = a_payload();
+        payload.repository.private_key = Some(PRIVATE_KEY.to_string());
+        payload.config.env.global = vec![Secure(ENCRYPTED_TOKEN.to_string())];
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&export_env_ast(&payload));
+
+        assert_eq!(Some(&"s3cret".to_string()), runner.shell_vars.find(&"TOKEN".to_string()));
+        assert_command_was_run(&runner, "export TOKEN");
+        assert_command_was_run(&runner, format!("echo {}", bash::shellescape("$ export TOKEN=[secure]")).as_slice());
+    }
+
+    /// The value can only be in the assignment, since the helpers print the commands they run.
+    #[test]
+    fn test_secure_env_display_is_masked() {
+        let mut payload = a_payload();
+        payload.repository.private_key = Some(PRIVATE_KEY.to_string());
+        payload.config.env.global = vec![Secure(ENCRYPTED_TOKEN.to_string())];
+
+        let bash = export_env_ast(&payload).to_bash().unwrap();
+        let lines: Vec<&str> = bash.as_slice().lines().filter(|line| line.contains("s3cret")).collect();
+
+        assert_eq!(vec!["TOKEN=s3cret"], lines);
+    }
+
+    #[test]
+    fn test_secure_env_skipped_for_pull_requests() {
+        let mut payload = a_payload();
+        payload.job.pull_request = true;
+        payload.repository.private_key = Some(PRIVATE_KEY.to_string());
+        payload.config.env.global = vec![Secure(ENCRYPTED_TOKEN.to_string()), Plain("FOO=bar".to_string())];
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&export_env_ast(&payload));
+
+        assert_eq!(None, runner.environment_vars.find(&"TOKEN".to_string()));
+        assert_eq!(Some(&"bar".to_string()), runner.environment_vars.find(&"FOO".to_string()));
+    }
+
+    #[test]
+    fn test_secure_env_without_key() {
+        let mut payload = a_payload();
+        payload.config.env.global = vec![Secure(ENCRYPTED_TOKEN.to_string())];
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&export_env_ast(&payload));
+
+        assert_eq!(None, runner.environment_vars.find(&"TOKEN".to_string()));
+        assert!(runner.commands.iter().any(|&(ref command, _)| command.as_slice().contains("decrypt a secure")), "expected a warning, got: {}", runner.commands);
+    }
+
+    #[test]
+    fn test_no_env() {
+        assert_eq!("", export_env_ast(&a_payload()).to_bash().unwrap().as_slice());
+    }
+}
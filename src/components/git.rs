@@ -1,23 +1,32 @@
 use ast;
-use payload::Payload;
+use payload::{Payload,Clone,Tarball};
 use bash;
 
 pub fn git_checkout_ast(payload: &Payload) -> ast::Statement {
     ast_block!(
         cmd!(ast::Mkdir(Path::new("/home/travis/build")));
         cmd!(ast::Cd(Path::new("build")));
+        match payload.config.git.strategy {
+            Clone => git_clone_ast(payload),
+            Tarball => git_tarball_ast(payload),
+        };
+    )
+}
+
+fn git_clone_ast(payload: &Payload) -> ast::Statement {
+    ast_block!(
         ast_set!(GIT_ASKPASS = "echo".to_string());
 
         ast_if! (!ast::IsDirectory(git_path(payload).join(Path::new(".git"))) {
-            format_cmd!([EchoOption|AssertOption], "git clone {} {} {}", git_clone_args(payload), git_source_url(payload), git_path(payload).as_str().unwrap());
+            format_cmd!([EchoOption|AssertOption|RetryOption], "git clone {} {} {}", git_clone_args(payload), git_source_url(payload), quoted_git_path(payload));
         } else {
-            format_cmd!([EchoOption|AssertOption], "git -C {} fetch origin", git_path(payload).as_str().unwrap());
-            format_cmd!([EchoOption|AssertOption], "git -C {} reset --hard", git_path(payload).as_str().unwrap());
+            format_cmd!([EchoOption|AssertOption|RetryOption], "git -C {} fetch origin", quoted_git_path(payload));
+            format_cmd!([EchoOption|AssertOption], "git -C {} reset --hard", quoted_git_path(payload));
         });
 
         cmd!(ast::Cd(git_path(payload)));
         match payload.job.git_ref {
-            Some(ref git_ref) => format_cmd!([EchoOption|AssertOption], "git fetch origin +{}:", git_ref),
+            Some(ref git_ref) => format_cmd!([EchoOption|AssertOption|RetryOption], "git fetch origin +{}:", git_ref),
             None => ast::Noop,
         };
 
@@ -26,7 +35,7 @@ pub fn git_checkout_ast(payload: &Payload) -> ast::Statement {
         if payload.config.git.submodules {
             ast_if! (ast::IsFile(Path::new(".gitmodules")) {
                 format_cmd!([EchoOption], "git submodule init");
-                format_cmd!([EchoOption], "git submodule update{}", submodules_args(payload));
+                format_cmd!([EchoOption|RetryOption], "git submodule update{}", submodules_args(payload));
             })
         } else {
             ast::Noop
@@ -34,10 +43,54 @@ pub fn git_checkout_ast(payload: &Payload) -> ast::Statement {
     )
 }
 
+/// The shell variable the `Authorization` header is passed to curl in. It's set outside of
+/// `travis_cmd` and isn't exported, so the token is never part of a command the helpers print.
+static AUTH_HEADER_VAR: &'static str = "TRAVIS_GIT_AUTH_HEADER";
+
+/// Downloads and extracts the archive of the commit instead of cloning. There's no repository to
+/// fetch submodules into, so they are skipped.
+fn git_tarball_ast(payload: &Payload) -> ast::Statement {
+    let tarball = bash::shellescape(tarball_name(payload).as_slice());
+    let url = tarball_url(payload);
+    let curl = |auth: &str| format!("curl -o {} {}-L {}", tarball, auth, bash::shellescape(url.as_slice()));
+
+    let download = match payload.oauth_token {
+        Some(ref token) => ast_block!(
+            ast::Assign(AUTH_HEADER_VAR.to_string(), format!("Authorization: token {}", token));
+            cmd!([EchoOption|AssertOption|RetryOption], ast::Raw(curl(format!("-H \"${}\" ", AUTH_HEADER_VAR).as_slice())));
+            ast::Assign(AUTH_HEADER_VAR.to_string(), "".to_string());
+        ),
+        None => cmd!([EchoOption|AssertOption|RetryOption], ast::Raw(curl(""))),
+    };
+
+    ast_block!(
+        cmd!(ast::Mkdir(git_path(payload)));
+        download;
+        format_cmd!([EchoOption|AssertOption], "tar xfz {} -C {} --strip-components=1", tarball, quoted_git_path(payload));
+        cmd!(ast::Cd(git_path(payload)));
+    )
+}
+
 fn git_path(payload: &Payload) -> Path {
     Path::new(payload.repository.slug.as_slice())
 }
 
+/// The path the repository is checked out to, quoted for use in a raw command.
+fn quoted_git_path(payload: &Payload) -> String {
+    bash::shellescape(payload.repository.slug.as_slice())
+}
+
+fn tarball_name(payload: &Payload) -> String {
+    format!("{}.tar.gz", payload.repository.slug.replace("/", "-"))
+}
+
+fn tarball_url(payload: &Payload) -> String {
+    match payload.repository.api_url {
+        Some(ref api_url) => format!("{}/tarball/{}", api_url, payload.job.commit),
+        None => format!("https://api.github.com/repos/{}/tarball/{}", payload.repository.slug, payload.job.commit),
+    }
+}
+
 fn git_clone_args(payload: &Payload) -> String {
     if payload.job.git_ref.is_some() {
         format!("--depth={}", payload.config.git.depth)
@@ -60,8 +113,11 @@ fn git_source_url(payload: &Payload) -> &str {
 #[cfg(test)]
 mod test {
     use super::git_checkout_ast;
+    use ast;
+    use bash::ToBash;
     use test_ast_runner::TestAstRunner;
     use payload::test::a_payload;
+    use payload::{Clone,Tarball};
 
     fn assert_command_was_run(runner: &TestAstRunner, expected: &str) {
         assert!(runner.commands.iter().any(|&(ref command, _)| command.as_slice() == expected), "expected command '{}' to be run, but wasn't: {}, (files: {})", expected, runner.commands, runner.fs_state);
@@ -112,7 +168,30 @@ mod test {
         let mut runner = TestAstRunner::new();
         runner.run(&script);
 
-        assert_command_was_run(&runner, "git clone --depth=50 --branch=a-\\>b git://github.com/example_owner/example_repo.git example_owner/example_repo");
+        assert_command_was_run(&runner, "git clone --depth=50 --branch='a->b' git://github.com/example_owner/example_repo.git example_owner/example_repo");
+    }
+
+    #[test]
+    fn test_git_clone_retries() {
+        let payload = a_payload();
+        let script = git_checkout_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.run(&script);
+
+        let options = runner.options_for("git clone --depth=50 --branch=master git://github.com/example_owner/example_repo.git example_owner/example_repo").unwrap();
+        assert!(options.contains(&ast::RetryOption), "expected clone to be retried, but options were {}", options);
+    }
+
+    #[test]
+    fn test_git_fetch_retries() {
+        let payload = a_payload();
+        let script = git_checkout_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.mkdir(&Path::new("/home/travis/build/example_owner/example_repo/.git"));
+        runner.run(&script);
+
+        let options = runner.options_for("git -C example_owner/example_repo fetch origin").unwrap();
+        assert!(options.contains(&ast::RetryOption), "expected fetch to be retried, but options were {}", options);
     }
 
     #[test]
@@ -206,4 +285,89 @@ mod test {
 
         assert_command_was_not_run_start(&runner, "git submodule");
     }
+
+    #[test]
+    fn test_clone_strategy_does_not_download_tarball() {
+        let mut payload = a_payload();
+        payload.config.git.strategy = Clone;
+
+        let script = git_checkout_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.run(&script);
+
+        assert_command_was_run(&runner, "git clone --depth=50 --branch=master git://github.com/example_owner/example_repo.git example_owner/example_repo");
+        assert_command_was_not_run_start(&runner, "curl");
+    }
+
+    #[test]
+    fn test_tarball_download() {
+        let mut payload = a_payload();
+        payload.config.git.strategy = Tarball;
+
+        let script = git_checkout_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.run(&script);
+
+        assert_command_was_run(&runner, "curl -o example_owner-example_repo.tar.gz -L https://api.github.com/repos/example_owner/example_repo/tarball/abcdef");
+        assert_command_was_run(&runner, "tar xfz example_owner-example_repo.tar.gz -C example_owner/example_repo --strip-components=1");
+        assert_command_was_not_run_start(&runner, "git");
+        assert_eq!(Some("/home/travis/build/example_owner/example_repo"), runner.working_directory.as_str());
+    }
+
+    #[test]
+    fn test_tarball_custom_api_url() {
+        let mut payload = a_payload();
+        payload.config.git.strategy = Tarball;
+        payload.repository.api_url = Some("https://github.example.com/api/v3/repos/example_owner/example_repo".to_string());
+
+        let script = git_checkout_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.run(&script);
+
+        assert_command_was_run(&runner, "curl -o example_owner-example_repo.tar.gz -L https://github.example.com/api/v3/repos/example_owner/example_repo/tarball/abcdef");
+    }
+
+    #[test]
+    fn test_tarball_auth_token() {
+        let mut payload = a_payload();
+        payload.config.git.strategy = Tarball;
+        payload.oauth_token = Some("secret".to_string());
+
+        let script = git_checkout_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.run(&script);
+
+        assert_command_was_run(&runner, "curl -o example_owner-example_repo.tar.gz -H \"Authorization: token secret\" -L https://api.github.com/repos/example_owner/example_repo/tarball/abcdef");
+        assert_eq!(Some(&"".to_string()), runner.shell_vars.find(&"TRAVIS_GIT_AUTH_HEADER".to_string()));
+    }
+
+    /// The helpers print the commands they run when they fail or retry them, so the token can't
+    /// be in any of them.
+    #[test]
+    fn test_tarball_auth_token_is_not_passed_to_travis_cmd() {
+        let mut payload = a_payload();
+        payload.config.git.strategy = Tarball;
+        payload.oauth_token = Some("secret".to_string());
+
+        let bash = git_checkout_ast(&payload).to_bash().unwrap();
+        let lines: Vec<&str> = bash.as_slice().lines().filter(|line| line.contains("secret")).collect();
+
+        assert_eq!(vec!["TRAVIS_GIT_AUTH_HEADER='Authorization: token secret'"], lines);
+    }
+
+    #[test]
+    fn test_tarball_skips_submodules() {
+        let mut payload = a_payload();
+        payload.config.git.strategy = Tarball;
+
+        let script = git_checkout_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.mkdir(&Path::new("/home/travis/build"));
+        runner.mkdir(&Path::new("/home/travis/build/example_owner"));
+        runner.mkdir(&Path::new("/home/travis/build/example_owner/example_repo"));
+        runner.put_file(&Path::new("/home/travis/build/example_owner/example_repo/.gitmodules"), b"hello there");
+        runner.run(&script);
+
+        assert_command_was_not_run_start(&runner, "git submodule");
+    }
 }
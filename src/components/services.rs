@@ -1,31 +1,47 @@
 use ast;
-use payload::Payload;
+use bash;
+use payload::{Payload,Service};
 
 pub fn start_services_ast(payload: &Payload) -> ast::Statement {
-    let stmts = payload.config.services.clone()
-        .map_in_place(normalize_service)
-        .iter()
-        .map(|service| format_cmd!([EchoOption], "sudo service {} start", service))
+    let stmts = payload.config.services.iter()
+        .map(|service| format_cmd!([EchoOption], "sudo {}service {} start{}", env_args(service), bash::shellescape(normalize_service(service.name.as_slice())), version_args(service)))
         .collect();
 
     ast::Statements(box stmts)
 }
 
-fn normalize_service(service: String) -> String {
-    match service.as_slice() {
-        "hbase" => "hbase-master".to_string(),
-        "memcache" => "memcached".to_string(),
-        "neo4j-server" => "neo4j".to_string(),
-        "rabbitmq" => "rabbitmq-server".to_string(),
-        "redis" => "redis-server".to_string(),
+fn normalize_service(service: &str) -> &str {
+    match service {
+        "hbase" => "hbase-master",
+        "memcache" => "memcached",
+        "neo4j-server" => "neo4j",
+        "rabbitmq" => "rabbitmq-server",
+        "redis" => "redis-server",
         _ => service,
     }
 }
 
+fn env_args(service: &Service) -> String {
+    if service.env.is_empty() {
+        return "".to_string();
+    }
+
+    let vars: Vec<String> = service.env.iter().map(|&(ref key, ref value)| format!("{}={}", key, bash::shellescape(value.as_slice()))).collect();
+    format!("env {} ", vars.connect(" "))
+}
+
+fn version_args(service: &Service) -> String {
+    match service.version {
+        Some(ref version) => format!(" {}", bash::shellescape(version.as_slice())),
+        None => "".to_string(),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::start_services_ast;
     use test_ast_runner::TestAstRunner;
+    use payload::Service;
     use payload::test::a_payload;
 
     fn assert_command_was_run(runner: &TestAstRunner, expected: &str) {
@@ -49,7 +65,7 @@ mod test {
     #[test]
     fn test_service_normalized() {
         let mut payload = a_payload();
-        payload.config.services = vec!["redis".to_string()];
+        payload.config.services = vec![Service::new("redis")];
 
         let script = start_services_ast(&payload);
         let mut runner = TestAstRunner::new();
@@ -61,7 +77,7 @@ mod test {
     #[test]
     fn test_service_not_normalized() {
         let mut payload = a_payload();
-        payload.config.services = vec!["elasticsearch".to_string()];
+        payload.config.services = vec![Service::new("elasticsearch")];
 
         let script = start_services_ast(&payload);
         let mut runner = TestAstRunner::new();
@@ -69,4 +85,20 @@ mod test {
 
         assert_command_was_run(&runner, "sudo service elasticsearch start");
     }
+
+    #[test]
+    fn test_service_version_and_env() {
+        let mut payload = a_payload();
+        payload.config.services = vec![Service {
+            name: "postgresql".to_string(),
+            version: Some("9.3".to_string()),
+            env: vec![("PGPORT".to_string(), "5433".to_string())],
+        }];
+
+        let script = start_services_ast(&payload);
+        let mut runner = TestAstRunner::new();
+        runner.run(&script);
+
+        assert_command_was_run(&runner, "sudo env PGPORT=5433 service postgresql start 9.3");
+    }
 }
@@ -0,0 +1,10 @@
+use languages::Language;
+
+/// The driver used for languages without one of their own. It leaves every stage to the config.
+pub struct Generic;
+
+impl Language for Generic {}
+
+pub fn new() -> Box<Language + 'static> {
+    box Generic as Box<Language>
+}
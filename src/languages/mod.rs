@@ -1,18 +1,159 @@
 use payload::Payload;
 use ast;
+use std::collections::HashMap;
 
-mod rust;
+pub mod generic;
+pub mod rust;
 
+/// A language driver generates the parts of the build that depend on the language of the project.
+///
+/// `install` and `script` are only used if the config doesn't specify its own commands for those stages.
 pub trait Language {
-    fn setup(&self) -> ast::Statement { ast::Noop }
-    fn announce(&self) -> ast::Statement { ast::Noop }
-    fn install(&self) -> ast::Statement { ast::Noop }
-    fn script(&self) -> ast::Statement { ast::Noop }
+    fn setup(&self, _payload: &Payload) -> ast::Statement { ast::Noop }
+    fn announce(&self, _payload: &Payload) -> ast::Statement { ast::Noop }
+    fn install(&self, _payload: &Payload) -> ast::Statement { ast::Noop }
+    fn script(&self, _payload: &Payload) -> ast::Statement { ast::Noop }
 }
 
-pub fn for_payload<L: Language>(payload: &Payload) -> L {
-    match payload.config.language.as_slice() {
-        "rust" => rust::Rust(payload),
-        _ => unimplemented!(),
+/// Creates a new instance of a language driver.
+pub type Driver = fn() -> Box<Language + 'static>;
+
+/// Maps the `language` key of the config to the driver for that language.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut registry = Registry::default();
+/// registry.register("haskell", haskell::new);
+/// registry.alias("hs", "haskell");
+///
+/// let script = Script::with_registry(payload, &registry);
+/// ```
+pub struct Registry {
+    drivers: HashMap<String, Driver>,
+    aliases: HashMap<String, String>,
+    fallback: Driver,
+}
+
+impl Registry {
+    /// Creates a registry without any drivers, which uses the generic driver for every language.
+    pub fn new() -> Registry {
+        Registry {
+            drivers: HashMap::new(),
+            aliases: HashMap::new(),
+            fallback: generic::new,
+        }
+    }
+
+    /// Creates a registry with the drivers and aliases that are built in to travis-build.
+    pub fn default() -> Registry {
+        let mut registry = Registry::new();
+
+        registry.register("rust", rust::new);
+
+        registry.alias("c++", "cpp");
+        registry.alias("jvm", "java");
+        registry.alias("node", "node_js");
+        registry.alias("objective-c", "objective_c");
+
+        registry
+    }
+
+    /// Registers a driver for a language, replacing any existing driver for it.
+    pub fn register(&mut self, name: &str, driver: Driver) {
+        self.drivers.insert(normalize(name), driver);
+    }
+
+    /// Makes `alias` use the same driver as `name`.
+    pub fn alias(&mut self, alias: &str, name: &str) {
+        self.aliases.insert(normalize(alias), normalize(name));
+    }
+
+    /// Sets the driver used for languages that aren't registered.
+    pub fn set_fallback(&mut self, driver: Driver) {
+        self.fallback = driver;
+    }
+
+    pub fn driver_for(&self, language: &str) -> Box<Language + 'static> {
+        let name = normalize(language);
+        let name = match self.aliases.find(&name) {
+            Some(target) => target.clone(),
+            None => name,
+        };
+
+        match self.drivers.find(&name) {
+            Some(driver) => (*driver)(),
+            None => (self.fallback)(),
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().chars().map(|c| c.to_lowercase()).collect()
+}
+
+/// Looks up the driver for the payload in the default registry.
+pub fn for_payload(payload: &Payload) -> Box<Language + 'static> {
+    Registry::default().driver_for(payload.config.language.as_slice())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Language,Registry};
+    use ast;
+    use bash::ToBash;
+    use payload::Payload;
+    use payload::test::a_payload;
+
+    struct Custom;
+
+    impl Language for Custom {
+        fn script(&self, _payload: &Payload) -> ast::Statement {
+            format_cmd!("custom")
+        }
+    }
+
+    fn new_custom() -> Box<Language + 'static> {
+        box Custom as Box<Language>
+    }
+
+    fn script_for(registry: &Registry, language: &str) -> String {
+        registry.driver_for(language).script(&a_payload()).to_bash().unwrap()
+    }
+
+    #[test]
+    fn test_builtin_driver() {
+        assert_eq!("travis_cmd 'cargo build --verbose && cargo test --verbose' --echo --result", script_for(&Registry::default(), "rust").as_slice());
+        assert_eq!("travis_cmd 'cargo build --verbose && cargo test --verbose' --echo --result", script_for(&Registry::default(), " Rust ").as_slice());
+    }
+
+    #[test]
+    fn test_unknown_language_uses_fallback() {
+        assert_eq!("", script_for(&Registry::default(), "brainfuck").as_slice());
+    }
+
+    #[test]
+    fn test_register_custom_driver() {
+        let mut registry = Registry::default();
+        registry.register("custom", new_custom);
+
+        assert_eq!("travis_cmd custom", script_for(&registry, "custom").as_slice());
+    }
+
+    #[test]
+    fn test_alias() {
+        let mut registry = Registry::new();
+        registry.register("cpp", new_custom);
+        registry.alias("c++", "cpp");
+
+        assert_eq!("travis_cmd custom", script_for(&registry, "c++").as_slice());
+    }
+
+    #[test]
+    fn test_custom_fallback() {
+        let mut registry = Registry::new();
+        registry.set_fallback(new_custom);
+
+        assert_eq!("travis_cmd custom", script_for(&registry, "anything").as_slice());
     }
 }
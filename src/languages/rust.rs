@@ -1,3 +1,127 @@
+use ast;
+use bash;
+use languages::Language;
 use payload::Payload;
 
-pub struct Rust<'a>(&'a Payload);
+pub struct Rust;
+
+pub fn new() -> Box<Language + 'static> {
+    box Rust as Box<Language>
+}
+
+impl Language for Rust {
+    fn setup(&self, payload: &Payload) -> ast::Statement {
+        ast::Fold("rust.install".to_string(), box ast_block! {
+            format_cmd!("mkdir -p $HOME/rust-installer");
+            format_cmd!([EchoOption|AssertOption|RetryOption], "curl -sL https://static.rust-lang.org/rustup.sh -o $HOME/rust-installer/rustup.sh");
+            ast::Cmd(ast::Raw(format!("sh $HOME/rust-installer/rustup.sh --prefix=$HOME/rust {} -y", toolchain_args(payload))),
+                     vec![ast::EchoOption, ast::AssertOption, ast::RetryOption, ast::DisplayOption(format!("Installing Rust ({})", payload.config.rust))]);
+        })
+    }
+
+    fn announce(&self, _payload: &Payload) -> ast::Statement {
+        ast_block! {
+            format_cmd!("export PATH=\"$HOME/rust/bin:$PATH\"");
+            format_cmd!("export LD_LIBRARY_PATH=\"$HOME/rust/lib:$LD_LIBRARY_PATH\"");
+            format_cmd!([EchoOption], "rustc --version");
+            format_cmd!([EchoOption], "cargo --version");
+        }
+    }
+
+    fn install(&self, _payload: &Payload) -> ast::Statement {
+        ast::Fold("install".to_string(), box format_cmd!([EchoOption|AssertOption|RetryOption|TimingOption], "cargo fetch"))
+    }
+
+    fn script(&self, _payload: &Payload) -> ast::Statement {
+        format_cmd!([EchoOption|ResultOption|TimingOption], "cargo build --verbose && cargo test --verbose")
+    }
+}
+
+/// Release channels are installed by name, anything else is treated as a pinned version.
+fn toolchain_args(payload: &Payload) -> String {
+    match payload.config.rust.as_slice() {
+        channel @ "stable" | channel @ "beta" | channel @ "nightly" => format!("--channel={}", channel),
+        version => format!("--revision={}", bash::shellescape(version)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Rust;
+    use ast;
+    use languages::Language;
+    use test_ast_runner::TestAstRunner;
+    use payload::test::a_payload;
+
+    fn assert_command_was_run(runner: &TestAstRunner, expected: &str) {
+        assert!(runner.commands.iter().any(|&(ref command, _)| command.as_slice() == expected), "expected command '{}' to be run, but wasn't: {}, (files: {})", expected, runner.commands, runner.fs_state);
+    }
+
+    #[test]
+    fn test_install_stable_by_default() {
+        let payload = a_payload();
+        let mut runner = TestAstRunner::new();
+        runner.run(&Rust.setup(&payload));
+
+        assert_command_was_run(&runner, "sh $HOME/rust-installer/rustup.sh --prefix=$HOME/rust --channel=stable -y");
+    }
+
+    #[test]
+    fn test_install_channel() {
+        let mut payload = a_payload();
+        payload.config.rust = "nightly".to_string();
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Rust.setup(&payload));
+
+        assert_command_was_run(&runner, "sh $HOME/rust-installer/rustup.sh --prefix=$HOME/rust --channel=nightly -y");
+    }
+
+    #[test]
+    fn test_install_pinned_version() {
+        let mut payload = a_payload();
+        payload.config.rust = "0.12.0".to_string();
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Rust.setup(&payload));
+
+        assert_command_was_run(&runner, "sh $HOME/rust-installer/rustup.sh --prefix=$HOME/rust --revision=0.12.0 -y");
+    }
+
+    /// The shell doesn't expand `~` after `=`, so every path is spelled with `$HOME`, the same way
+    /// `announce` adds the toolchain to the `PATH`.
+    #[test]
+    fn test_installs_into_the_path() {
+        let payload = a_payload();
+        let mut runner = TestAstRunner::new();
+        runner.run(&Rust.setup(&payload));
+        runner.run(&Rust.announce(&payload));
+
+        assert_command_was_run(&runner, "mkdir -p $HOME/rust-installer");
+        assert_command_was_run(&runner, "curl -sL https://static.rust-lang.org/rustup.sh -o $HOME/rust-installer/rustup.sh");
+        assert_command_was_run(&runner, "sh $HOME/rust-installer/rustup.sh --prefix=$HOME/rust --channel=stable -y");
+        assert_command_was_run(&runner, "export PATH=\"$HOME/rust/bin:$PATH\"");
+    }
+
+    #[test]
+    fn test_announce() {
+        let payload = a_payload();
+        let mut runner = TestAstRunner::new();
+        runner.run(&Rust.announce(&payload));
+
+        assert_command_was_run(&runner, "rustc --version");
+        assert_command_was_run(&runner, "cargo --version");
+    }
+
+    #[test]
+    fn test_default_install_and_script() {
+        let payload = a_payload();
+        let mut runner = TestAstRunner::new();
+        runner.run(&Rust.install(&payload));
+        runner.run(&Rust.script(&payload));
+
+        assert_command_was_run(&runner, "cargo fetch");
+        assert_command_was_run(&runner, "cargo build --verbose && cargo test --verbose");
+        assert!(runner.options_for("cargo fetch").unwrap().contains(&ast::RetryOption));
+    }
+}
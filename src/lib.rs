@@ -6,6 +6,10 @@
 #![experimental]
 #![feature(macro_rules)]
 
+extern crate flate;
+extern crate openssl;
+#[cfg(test)]
+extern crate regex;
 extern crate serialize;
 
 pub use script::Script;
@@ -14,8 +18,15 @@ pub use payload::Payload;
 pub mod ast;
 pub mod bash;
 pub mod payload;
+pub mod putfile;
+pub mod quote;
 pub mod script;
 pub mod script_templates;
+pub mod secure;
+pub mod sh;
+pub mod source_map;
 pub mod components;
 pub mod test_ast_runner;
 pub mod languages;
+pub mod matrix;
+pub mod yaml;
@@ -1,18 +1,198 @@
+extern crate getopts;
 extern crate serialize;
 extern crate travis_build;
 
+use getopts::{optopt, optflag, getopts, usage, Matches, OptGroup};
 use serialize::json;
+use serialize::json::ToJson;
+use std::io;
+use std::io::File;
+use std::os;
+use travis_build::ast;
+use travis_build::payload::{Config, Job, Payload, Repository, Bash, Posix};
+use travis_build::script;
 
 fn main() {
-    let payload_str = "{\"repository\":{\"slug\":\"henrikhodne/test\",\"source_url\":\"git://github.com/henrikhodne/test.git\"},\"job\":{\"commit\":\"abcdef\",\"branch\":\"master\",\"pull_request\":false},\"config\":{\"language\":\"rust\",\"os\":\"linux\"}}";
-    let payload_json = match json::from_str(payload_str) {
-        Ok(p) => p,
-        Err(e) => panic!("couldn't parse JSON: {}", e)
+    let args = os::args();
+    let program = args[0].clone();
+
+    let opts = [
+        optopt("o", "output", "write the script to FILE instead of stdout", "FILE"),
+        optflag("", "validate-only", "only check that the payload is valid, don't print the script"),
+        optopt("", "emit", "what to write: the bash script (the default) or the AST as JSON", "bash|ast-json"),
+        optopt("", "ast", "render the AST in the JSON FILE instead of building one from a payload", "FILE"),
+        optopt("", "shell", "the shell to write the script for (default: the payload's shell, or bash)", "bash|posix"),
+        optopt("", "source-map", "write which config entry or component each line of the script came from to FILE", "FILE"),
+        optflag("", "no-color", "print the script's messages without colours"),
+        optopt("c", "config", "read a .travis.yml config from FILE instead of a JSON payload", "FILE"),
+        optopt("", "slug", "the owner/name of the repository, when using --config", "SLUG"),
+        optopt("", "source-url", "the URL to clone the repository from, when using --config", "URL"),
+        optopt("", "commit", "the commit to build, when using --config", "SHA"),
+        optopt("", "branch", "the branch to build, when using --config (default: master)", "BRANCH"),
+        optopt("", "ref", "the ref to fetch before checking out the commit, when using --config", "REF"),
+        optflag("", "pull-request", "build the job as a pull request, when using --config"),
+        optflag("h", "help", "print this help message"),
+    ];
+
+    let matches = match getopts(args.tail(), opts) {
+        Ok(m) => m,
+        Err(f) => return fail(&program, f.to_string()),
+    };
+
+    if matches.opt_present("help") {
+        print_usage(program.as_slice(), opts);
+        return;
+    }
+
+    if matches.free.len() > 1 {
+        return fail(&program, "expected at most one payload file".to_string());
+    }
+
+    match run(&matches) {
+        Ok(()) => {},
+        Err(e) => fail(&program, e),
+    }
+}
+
+fn run(matches: &Matches) -> Result<(), String> {
+    let emit = matches.opt_str("emit").unwrap_or("bash".to_string());
+    if emit.as_slice() != "bash" && emit.as_slice() != "ast-json" {
+        return Err(format!("unknown --emit format {}, expected bash or ast-json", emit));
+    }
+    if emit.as_slice() == "ast-json" && matches.opt_present("source-map") {
+        return Err("--source-map maps lines of the script, so it can't be used with --emit=ast-json".to_string());
+    }
+
+    let shell_option = match matches.opt_str("shell") {
+        Some(ref shell) if shell.as_slice() == "bash" => Some(Bash),
+        Some(ref shell) if shell.as_slice() == "posix" => Some(Posix),
+        Some(shell) => return Err(format!("unknown --shell {}, expected bash or posix", shell)),
+        None => None,
     };
-    let payload = match travis_build::Payload::from_json(&payload_json) {
+
+    let (ast, payload_shell) = match matches.opt_str("ast") {
+        Some(ref ast_path) => {
+            let ast = try!(ast_from_json(ast_path));
+            if matches.opt_present("no-color") {
+                (ast::Statements(box vec![script::no_color_ast(), ast]), Bash)
+            } else {
+                (ast, Bash)
+            }
+        },
+        None => {
+            let mut payload = match matches.opt_str("config") {
+                Some(ref config_path) => try!(payload_from_config(matches, config_path)),
+                None => try!(payload_from_json(matches.free.as_slice().head())),
+            };
+            if matches.opt_present("no-color") {
+                payload.no_color = true;
+            }
+            let payload_shell = payload.shell;
+            (travis_build::Script::new(payload).to_ast(), payload_shell)
+        },
+    };
+    let shell = shell_option.unwrap_or(payload_shell);
+
+    // A payload can decode and still have something in it that can't be written to the script, so
+    // it is only valid once the script renders. The script is thrown away.
+    if matches.opt_present("validate-only") {
+        try!(script::render(&ast, shell).map_err(|e| format!("couldn't render the script: {}", e)));
+        return Ok(());
+    }
+
+    let output = match emit.as_slice() {
+        "ast-json" => format!("{}\n", ast.to_json().to_pretty_str()),
+        _ => {
+            let (script, mappings) = try!(script::render_with_source_map(&ast, shell).map_err(|e| format!("couldn't render the script: {}", e)));
+            match matches.opt_str("source-map") {
+                Some(ref path) => try!(write_file(path, format!("{}\n", mappings.to_json().to_pretty_str()).as_slice())),
+                None => {},
+            }
+            script
+        },
+    };
+    write_output(matches.opt_str("output"), output.as_slice())
+}
+
+/// Reads an AST in the format described in `travis_build::ast::json`.
+fn ast_from_json(path: &String) -> Result<ast::Statement, String> {
+    let ast_str = try!(read_input(Some(path)));
+    let ast_json = match json::from_str(ast_str.as_slice()) {
+        Ok(j) => j,
+        Err(e) => return Err(format!("couldn't parse JSON: {}", e)),
+    };
+
+    ast::Statement::from_json(&ast_json, "ast").map_err(|e| format!("invalid AST: {}", error_message(e)))
+}
+
+fn payload_from_json(input: Option<&String>) -> Result<Payload, String> {
+    let payload_str = try!(read_input(input));
+    let payload_json = match json::from_str(payload_str.as_slice()) {
         Ok(p) => p,
-        Err(e) => panic!("couldn't parse JSON to payload: {}", e)
+        Err(e) => return Err(format!("couldn't parse JSON: {}", e)),
+    };
+
+    Payload::from_json(&payload_json).map_err(|e| format!("invalid payload: {}", error_message(e)))
+}
+
+/// Builds a payload from a YAML config and the job and repository given as flags.
+fn payload_from_config(matches: &Matches, config_path: &String) -> Result<Payload, String> {
+    let config_str = try!(read_input(Some(config_path)));
+    let config = try!(Config::from_yaml(config_str.as_slice()).map_err(|e| format!("invalid config: {}", error_message(e))));
+
+    let slug = try!(matches.opt_str("slug").ok_or("--slug is required with --config".to_string()));
+    let commit = try!(matches.opt_str("commit").ok_or("--commit is required with --config".to_string()));
+    let source_url = matches.opt_str("source-url").unwrap_or_else(|| format!("git://github.com/{}.git", slug));
+
+    let job = Job {
+        branch: matches.opt_str("branch").unwrap_or("master".to_string()),
+        commit: commit,
+        git_ref: matches.opt_str("ref"),
+        pull_request: matches.opt_present("pull-request"),
+    };
+    let repository = Repository {
+        slug: slug,
+        source_url: source_url,
+        api_url: None,
+        private_key: None,
     };
 
-    print!("{}", travis_build::Script::new(payload).to_script());
+    Ok(Payload::new(job, repository, config))
+}
+
+fn error_message(error: json::DecoderError) -> String {
+    match error {
+        json::ApplicationError(message) => message,
+        json::MissingFieldError(field) => format!("{} is missing", field),
+        e => e.to_string(),
+    }
+}
+
+/// Reads the payload from the given file, or from stdin if there is no file or it is `-`.
+fn read_input(input: Option<&String>) -> Result<String, String> {
+    match input {
+        Some(path) if path.as_slice() != "-" => File::open(&Path::new(path.as_slice())).read_to_string().map_err(|e| format!("couldn't read {}: {}", path, e)),
+        _ => io::stdin().read_to_string().map_err(|e| format!("couldn't read stdin: {}", e)),
+    }
+}
+
+fn write_output(output: Option<String>, script: &str) -> Result<(), String> {
+    match output {
+        Some(ref path) if path.as_slice() != "-" => write_file(path, script),
+        _ => io::stdout().write_str(script).map_err(|e| format!("couldn't write to stdout: {}", e)),
+    }
+}
+
+fn write_file(path: &String, contents: &str) -> Result<(), String> {
+    File::create(&Path::new(path.as_slice())).write_str(contents).map_err(|e| format!("couldn't write {}: {}", path, e))
+}
+
+fn print_usage(program: &str, opts: &[OptGroup]) {
+    let brief = format!("Usage: {0} [options] [PAYLOAD]\n       {0} [options] --config .travis.yml --slug SLUG --commit SHA\n       {0} [options] --ast AST\n\nReads a JSON payload from PAYLOAD (or stdin), or a .travis.yml config, and prints the build script.", program);
+    print!("{}", usage(brief.as_slice(), opts));
+}
+
+fn fail(program: &String, message: String) {
+    let _ = writeln!(&mut io::stderr(), "{}: {}", program, message);
+    os::set_exit_status(1);
 }
@@ -0,0 +1,265 @@
+//! Expands the build matrix of a config into the jobs it describes.
+//!
+//! Every key in `AXES` whose value is a list adds a dimension to the matrix, and a job is created
+//! for each combination of values. Jobs matching an entry in `matrix.exclude` are removed, and an
+//! extra job is added for each entry in `matrix.include`. If `env` has `global` and `matrix` keys,
+//! only the `matrix` variables are expanded, and each job keeps the `global` ones.
+
+use payload::Payload;
+use serialize::json;
+use std::collections::TreeMap;
+
+/// The keys of the config that can be expanded, in the order they are expanded in.
+pub static AXES: &'static [&'static str] = &[
+    "os", "compiler", "rust", "rvm", "gemfile", "python", "node_js", "jdk",
+    "go", "php", "perl", "scala", "ghc", "otp_release", "env",
+];
+
+pub struct Matrix {
+    pub jobs: Vec<MatrixJob>,
+    /// Whether the build should finish as soon as the jobs that aren't allowed to fail have finished.
+    pub fast_finish: bool,
+}
+
+pub struct MatrixJob {
+    pub payload: Payload,
+    /// Whether the job is allowed to fail without failing the build.
+    pub allow_failure: bool,
+}
+
+type ConfigObject = TreeMap<String, json::Json>;
+
+impl Matrix {
+    /// Expands a payload whose config may describe several jobs.
+    pub fn expand(payload: &json::Json) -> json::DecodeResult<Matrix> {
+        let payload = match *payload {
+            json::Object(ref payload) => payload,
+            _ => return Err(json::ApplicationError(format!("payload: expected object, got {}", payload))),
+        };
+        let mut config = match payload.find(&"config".to_string()) {
+            Some(&json::Object(ref config)) => config.clone(),
+            Some(other) => return Err(json::ApplicationError(format!("config: expected object, got {}", other))),
+            None => return Err(json::MissingFieldError("config".to_string())),
+        };
+
+        let settings = config.pop(&"matrix".to_string()).unwrap_or(json::Object(TreeMap::new()));
+        if !settings.is_object() && !settings.is_null() {
+            return Err(json::ApplicationError(format!("config.matrix: expected object, got {}", settings)));
+        }
+
+        let axes = take_axes(&mut config);
+        let include = try!(entries(&settings, "include"));
+        let exclude = try!(entries(&settings, "exclude"));
+        let allow_failures = try!(entries(&settings, "allow_failures"));
+        let fast_finish = match settings.find(&"fast_finish".to_string()) {
+            Some(&json::Boolean(fast_finish)) => fast_finish,
+            Some(&json::Null) | None => false,
+            Some(other) => return Err(json::ApplicationError(format!("config.matrix.fast_finish: expected boolean, got {}", other))),
+        };
+
+        let mut configs: Vec<ConfigObject> = product(axes.as_slice()).into_iter()
+            .map(|assignment| merge(&config, &assignment))
+            .filter(|job_config| !exclude.iter().any(|entry| matches(entry, job_config)))
+            .collect();
+        configs.extend(include.iter().map(|entry| merge(&config, entry)));
+
+        let mut jobs = Vec::with_capacity(configs.len());
+        for job_config in configs.into_iter() {
+            let allow_failure = allow_failures.iter().any(|entry| matches(entry, &job_config));
+
+            let mut job_payload = payload.clone();
+            job_payload.insert("config".to_string(), json::Object(job_config));
+
+            jobs.push(MatrixJob {
+                payload: try!(Payload::from_json(&json::Object(job_payload))),
+                allow_failure: allow_failure,
+            });
+        }
+
+        Ok(Matrix { jobs: jobs, fast_finish: fast_finish })
+    }
+}
+
+/// Removes the axes from the config, leaving the first value of each in its place.
+fn take_axes(config: &mut ConfigObject) -> Vec<(String, Vec<json::Json>)> {
+    let mut axes = Vec::new();
+
+    for key in AXES.iter() {
+        let key = key.to_string();
+        let values = match (key.as_slice(), config.find(&key)) {
+            (_, Some(&json::List(ref values))) => values.clone(),
+            ("env", Some(&json::Object(ref env))) => match env.find(&"matrix".to_string()) {
+                Some(&json::List(ref values)) => values.iter().map(|value| with_env_matrix(env, value)).collect(),
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        match values.as_slice().head() {
+            Some(first) => config.insert(key.clone(), first.clone()),
+            None => config.remove(&key),
+        };
+
+        if !values.is_empty() {
+            axes.push((key, values));
+        }
+    }
+
+    axes
+}
+
+fn with_env_matrix(env: &ConfigObject, value: &json::Json) -> json::Json {
+    let mut env = env.clone();
+    env.insert("matrix".to_string(), value.clone());
+    json::Object(env)
+}
+
+/// Returns every combination of the values of the axes.
+fn product(axes: &[(String, Vec<json::Json>)]) -> Vec<ConfigObject> {
+    let mut result = vec![TreeMap::new()];
+
+    for &(ref key, ref values) in axes.iter() {
+        let mut next = Vec::with_capacity(result.len() * values.len());
+        for assignment in result.iter() {
+            for value in values.iter() {
+                let mut assignment = assignment.clone();
+                assignment.insert(key.clone(), value.clone());
+                next.push(assignment);
+            }
+        }
+        result = next;
+    }
+
+    result
+}
+
+/// Sets the keys of the entry in the config.
+///
+/// An `env` entry replaces the `matrix` variables of the config if it has `global` ones too, so the
+/// job keeps those.
+fn merge(config: &ConfigObject, overrides: &ConfigObject) -> ConfigObject {
+    let mut result = config.clone();
+    for (key, value) in overrides.iter() {
+        let value = match (key.as_slice(), config.find(key)) {
+            ("env", Some(&json::Object(ref env))) if !value.is_object() => with_env_matrix(env, value),
+            _ => value.clone(),
+        };
+        result.insert(key.clone(), value);
+    }
+    result
+}
+
+/// Whether every key of the entry has the same value in the job's config.
+///
+/// An `env` entry is compared to the `matrix` variables of the job if it has `global` ones too.
+fn matches(entry: &ConfigObject, config: &ConfigObject) -> bool {
+    entry.iter().all(|(key, value)| {
+        match (key.as_slice(), config.find(key)) {
+            ("env", Some(&json::Object(ref env))) if !value.is_object() => env.find(&"matrix".to_string()) == Some(value),
+            (_, job_value) => job_value == Some(value),
+        }
+    })
+}
+
+fn entries(settings: &json::Json, key: &str) -> json::DecodeResult<Vec<ConfigObject>> {
+    let list = match settings.find(&key.to_string()) {
+        Some(&json::List(ref list)) => list,
+        Some(&json::Null) | None => return Ok(vec![]),
+        Some(other) => return Err(json::ApplicationError(format!("config.matrix.{}: expected list, got {}", key, other))),
+    };
+
+    let mut result = Vec::with_capacity(list.len());
+    for (i, entry) in list.iter().enumerate() {
+        match *entry {
+            json::Object(ref entry) => result.push(entry.clone()),
+            _ => return Err(json::ApplicationError(format!("config.matrix.{}[{}]: expected object, got {}", key, i, entry))),
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Matrix;
+    use payload::{EnvEntry,Plain};
+    use serialize::json;
+
+    fn try_expand(config: &str) -> json::DecodeResult<Matrix> {
+        let payload = format!("{{\"repository\":{{\"slug\":\"a/b\",\"source_url\":\"git://github.com/a/b.git\"}},\"job\":{{\"commit\":\"abcdef\",\"branch\":\"master\",\"pull_request\":false}},\"config\":{}}}", config);
+        Matrix::expand(&json::from_str(payload.as_slice()).unwrap())
+    }
+
+    fn expand(config: &str) -> Matrix {
+        match try_expand(config) {
+            Ok(matrix) => matrix,
+            Err(e) => panic!("couldn't expand {}: {}", config, e),
+        }
+    }
+
+    fn jobs(matrix: &Matrix) -> Vec<(&str, &str)> {
+        matrix.jobs.iter().map(|job| (job.payload.config.os.as_slice(), job.payload.config.rust.as_slice())).collect()
+    }
+
+    #[test]
+    fn test_single_job() {
+        let matrix = expand("{\"rust\":\"nightly\"}");
+
+        assert_eq!(vec![("linux", "nightly")], jobs(&matrix));
+        assert!(!matrix.fast_finish);
+    }
+
+    #[test]
+    fn test_expand_axes() {
+        let matrix = expand("{\"os\":[\"linux\",\"osx\"],\"rust\":[\"stable\",\"nightly\"]}");
+
+        assert_eq!(vec![("linux", "stable"), ("linux", "nightly"), ("osx", "stable"), ("osx", "nightly")], jobs(&matrix));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let matrix = expand("{\"os\":[\"linux\",\"osx\"],\"rust\":[\"stable\",\"nightly\"],\"matrix\":{\"exclude\":[{\"os\":\"osx\",\"rust\":\"nightly\"}]}}");
+
+        assert_eq!(vec![("linux", "stable"), ("linux", "nightly"), ("osx", "stable")], jobs(&matrix));
+    }
+
+    #[test]
+    fn test_include() {
+        let matrix = expand("{\"rust\":[\"stable\",\"nightly\"],\"matrix\":{\"include\":[{\"os\":\"osx\"}]}}");
+
+        assert_eq!(vec![("linux", "stable"), ("linux", "nightly"), ("osx", "stable")], jobs(&matrix));
+    }
+
+    #[test]
+    fn test_allow_failures_and_fast_finish() {
+        let matrix = expand("{\"rust\":[\"stable\",\"nightly\"],\"matrix\":{\"allow_failures\":[{\"rust\":\"nightly\"}],\"fast_finish\":true}}");
+
+        let allowed: Vec<bool> = matrix.jobs.iter().map(|job| job.allow_failure).collect();
+        assert_eq!(vec![false, true], allowed);
+        assert!(matrix.fast_finish);
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let matrix = expand("{\"env\":{\"global\":\"A=1\",\"matrix\":[\"B=2\",\"B=3\",\"B=4\"]},\"matrix\":{\"exclude\":[{\"env\":\"B=3\"}]}}");
+
+        let envs: Vec<(Vec<EnvEntry>, Vec<EnvEntry>)> = matrix.jobs.iter().map(|job| (job.payload.config.env.global.clone(), job.payload.config.env.matrix.clone())).collect();
+        assert_eq!(vec![(vec![Plain("A=1".to_string())], vec![Plain("B=2".to_string())]), (vec![Plain("A=1".to_string())], vec![Plain("B=4".to_string())])], envs);
+    }
+
+    #[test]
+    fn test_include_env_keeps_global_env() {
+        let matrix = expand("{\"env\":{\"global\":\"A=1\",\"matrix\":[\"B=2\"]},\"matrix\":{\"include\":[{\"env\":\"C=3\"}]}}");
+
+        let envs: Vec<(Vec<EnvEntry>, Vec<EnvEntry>)> = matrix.jobs.iter().map(|job| (job.payload.config.env.global.clone(), job.payload.config.env.matrix.clone())).collect();
+        assert_eq!(vec![(vec![Plain("A=1".to_string())], vec![Plain("B=2".to_string())]), (vec![Plain("A=1".to_string())], vec![Plain("C=3".to_string())])], envs);
+    }
+
+    #[test]
+    fn test_invalid_matrix() {
+        match try_expand("{\"matrix\":{\"include\":[\"osx\"]}}") {
+            Err(json::ApplicationError(message)) => assert_eq!("config.matrix.include[0]: expected object, got \"osx\"", message.as_slice()),
+            _ => panic!("expected an error"),
+        }
+    }
+}
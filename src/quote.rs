@@ -0,0 +1,196 @@
+//! Quotes strings so the shell reads them back byte for byte, whatever the locale.
+//!
+//! Words that only contain characters that are never special are left alone. Everything else is
+//! put in single quotes, which keep every byte as it is. Bash can't show control characters or
+//! bytes that aren't UTF-8 in a readable way inside single quotes, so `quote` uses ANSI-C quoting
+//! (`$'...'`) for those. POSIX sh doesn't have that, so `quote_posix` writes such bytes with
+//! `printf` instead.
+
+use std::fmt;
+
+/// The shell can't hold a NUL byte in a word, so input that contains one can't be quoted.
+#[deriving(Clone, PartialEq)]
+pub struct QuoteError {
+    /// Where the first NUL byte is, in bytes from the start of the input.
+    pub offset: uint,
+}
+
+impl fmt::Show for QuoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NUL byte at offset {}", self.offset)
+    }
+}
+
+pub type QuoteResult = Result<String, QuoteError>;
+
+/// Quotes the input for bash.
+pub fn quote(input: &[u8]) -> QuoteResult {
+    match try!(simple_quote(input)) {
+        Some(quoted) => Ok(quoted),
+        None => Ok(ansi_c_quote(input)),
+    }
+}
+
+/// Quotes the input for POSIX sh.
+pub fn quote_posix(input: &[u8]) -> QuoteResult {
+    match try!(simple_quote(input)) {
+        Some(quoted) => Ok(quoted),
+        None => Ok(printf_quote(input)),
+    }
+}
+
+fn is_safe(byte: u8) -> bool {
+    (byte >= b'A' && byte <= b'Z') || (byte >= b'a' && byte <= b'z') || (byte >= b'0' && byte <= b'9') || b"_-.,:/@".contains(&byte)
+}
+
+fn is_control(byte: u8) -> bool {
+    byte < 0x20 || byte == 0x7f
+}
+
+/// Quotes the input the same way for both shells, or returns `None` if it has control characters
+/// or isn't UTF-8.
+fn simple_quote(input: &[u8]) -> Result<Option<String>, QuoteError> {
+    match input.iter().position(|&byte| byte == 0) {
+        Some(offset) => return Err(QuoteError { offset: offset }),
+        None => {},
+    }
+
+    if input.is_empty() {
+        return Ok(Some("''".to_string()));
+    }
+
+    if input.iter().all(|&byte| is_safe(byte)) {
+        return Ok(Some(input.iter().map(|&byte| byte as char).collect()));
+    }
+
+    if input.iter().any(|&byte| is_control(byte)) {
+        return Ok(None);
+    }
+
+    match String::from_utf8(input.to_vec()) {
+        Ok(string) => Ok(Some(single_quote(string.as_slice()))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// A single quote can't be escaped inside single quotes, so it ends the quoted part, is escaped
+/// on its own and starts a new one.
+fn single_quote(input: &str) -> String {
+    format!("'{}'", input.replace("'", "'\\''"))
+}
+
+fn ansi_c_quote(input: &[u8]) -> String {
+    let mut output = "$'".to_string();
+    for &byte in input.iter() {
+        match byte {
+            b'\n' => output.push_str("\\n"),
+            b'\t' => output.push_str("\\t"),
+            b'\r' => output.push_str("\\r"),
+            b'\\' => output.push_str("\\\\"),
+            b'\'' => output.push_str("\\'"),
+            0x20...0x7e => output.push(byte as char),
+            _ => output.push_str(format!("\\x{:02x}", byte).as_slice()),
+        }
+    }
+    output.push('\'');
+    output
+}
+
+/// Single quotes the ASCII parts of the input, and has `printf` write the other bytes. Control
+/// characters can be in single quotes as they are, but bytes that aren't UTF-8 can't be in the
+/// script.
+fn printf_quote(input: &[u8]) -> String {
+    let mut output = String::new();
+    let mut ascii = String::new();
+    for &byte in input.iter() {
+        if byte < 0x80 {
+            ascii.push(byte as char);
+        } else {
+            if !ascii.is_empty() {
+                output.push_str(single_quote(ascii.as_slice()).as_slice());
+                ascii.clear();
+            }
+            output.push_str(format!("\"$(printf '\\{:03o}')\"", byte).as_slice());
+        }
+    }
+    if !ascii.is_empty() {
+        output.push_str(single_quote(ascii.as_slice()).as_slice());
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::{QuoteError, quote, quote_posix};
+    use std::io::process::Command;
+
+    /// Strings that are hard to quote.
+    fn inputs() -> Vec<Vec<u8>> {
+        vec![
+            b"".to_vec(),
+            b"plain-word_1.0,a:b/c@d".to_vec(),
+            b"hello world".to_vec(),
+            b"it's \"quoted\"".to_vec(),
+            b"$HOME `id` $(id) \\ * ? [a] ~ # ! ; & | < > { } =".to_vec(),
+            "feature/\u00fcber-caf\u00e9-\u65e5\u672c".as_bytes().to_vec(),
+            b"line one\nline two\n".to_vec(),
+            b"\t\r\x01\x1b[31mred\x1b[0m\x7f".to_vec(),
+            b"\xff\xfe not utf-8 \xc3".to_vec(),
+            b"'".to_vec(),
+            b"\\x41\\'".to_vec(),
+        ]
+    }
+
+    /// Runs `printf %s` with the quoted input as its argument, and returns what it printed.
+    fn evaluate(shell: &str, quoted: &str) -> Vec<u8> {
+        let script = format!("printf %s {}", quoted);
+        match Command::new(shell).arg("-c").arg(script.as_slice()).output() {
+            Ok(output) => {
+                assert!(output.status.success(), "{} failed to run {}", shell, script);
+                output.output
+            },
+            Err(e) => panic!("couldn't run {}: {}", shell, e),
+        }
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(Ok("''".to_string()), quote(b""));
+        assert_eq!(Ok("feature/branch-1.0".to_string()), quote(b"feature/branch-1.0"));
+        assert_eq!(Ok("'hello world'".to_string()), quote(b"hello world"));
+        assert_eq!(Ok("'it'\\''s'".to_string()), quote(b"it's"));
+        assert_eq!(Ok("'caf\u00e9'".to_string()), quote("caf\u00e9".as_bytes()));
+        assert_eq!(Ok("$'a\\nb\\x1b\\'c'".to_string()), quote(b"a\nb\x1b'c"));
+        assert_eq!(Ok("$'caf\\xc3'".to_string()), quote(b"caf\xc3"));
+    }
+
+    #[test]
+    fn test_quote_posix() {
+        assert_eq!(Ok("'hello world'".to_string()), quote_posix(b"hello world"));
+        assert_eq!(Ok("'a\nb'".to_string()), quote_posix(b"a\nb"));
+        assert_eq!(Ok("'caf'\"$(printf '\\303')\"".to_string()), quote_posix(b"caf\xc3"));
+    }
+
+    #[test]
+    fn test_nul_bytes_cant_be_quoted() {
+        assert_eq!(Err(QuoteError { offset: 3 }), quote(b"abc\x00def"));
+        assert_eq!(Err(QuoteError { offset: 0 }), quote_posix(b"\x00"));
+        assert_eq!("NUL byte at offset 3", QuoteError { offset: 3 }.to_string().as_slice());
+    }
+
+    #[test]
+    fn test_round_trip_through_bash() {
+        for input in inputs().iter() {
+            let quoted = quote(input.as_slice()).unwrap();
+            assert!(&evaluate("bash", quoted.as_slice()) == input, "bash changed {}", quoted);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_through_sh() {
+        for input in inputs().iter() {
+            let quoted = quote_posix(input.as_slice()).unwrap();
+            assert!(&evaluate("sh", quoted.as_slice()) == input, "sh changed {}", quoted);
+        }
+    }
+}
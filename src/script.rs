@@ -1,27 +1,37 @@
-use payload::Payload;
+use payload::{Payload,Shell,Bash,Posix};
 use ast;
-use bash::ToBash;
+use ast::normalize::normalize;
+use bash::{RenderResult, ToBash};
+use sh::ToSh;
 use components;
-use script_templates::{SCRIPT_HEADER,SCRIPT_FOOTER};
+use languages::{Language,Registry};
+use script_templates::{SCRIPT_HEADER,SCRIPT_FOOTER,POSIX_SCRIPT_HEADER,POSIX_SCRIPT_FOOTER};
+use source_map::{SourceMapping,source_map};
 
 pub struct Script {
     payload: Payload,
+    language: Box<Language + 'static>,
 }
 
 impl Script {
     pub fn new(payload: Payload) -> Script {
-        Script { payload: payload }
+        Script::with_registry(payload, &Registry::default())
     }
 
-    pub fn to_script(&self) -> String {
-        let ast = self.generate_ast();
+    /// Creates a script using the language drivers from the given registry.
+    pub fn with_registry(payload: Payload, registry: &Registry) -> Script {
+        let language = registry.driver_for(payload.config.language.as_slice());
+        Script { payload: payload, language: language }
+    }
 
-        let mut script = SCRIPT_HEADER.to_string();
-        script.push_str(ast.to_bash().as_slice());
-        script.push('\n');
-        script.push_str(SCRIPT_FOOTER);
+    /// Renders the build script, which fails if part of the payload can't be written to it.
+    pub fn to_script(&self) -> RenderResult<String> {
+        render(&self.to_ast(), self.payload.shell)
+    }
 
-        script
+    /// The normalized AST of the build, without the helper functions and the footer of the script.
+    pub fn to_ast(&self) -> ast::Statement {
+        normalize(self.generate_ast())
     }
 
     fn generate_ast(&self) -> ast::Statement {
@@ -33,22 +43,61 @@ impl Script {
 
     fn builtin_stages_ast(&self) -> ast::Statement {
         ast_block! {
-            self.apply_fixes();
-            components::git::git_checkout_ast(&self.payload);
-            components::services::start_services_ast(&self.payload);
-            self.enable_paranoid_mode();
-            self.export_vars();
+            self.disable_colors();
+            self.export_retry_policy();
+            stage("configure", self.apply_fixes());
+            stage("checkout", traced("components::git", components::git::git_checkout_ast(&self.payload)));
+            stage("prepare", traced("components::services", components::services::start_services_ast(&self.payload)));
+            stage("disable_sudo", self.enable_paranoid_mode());
+            stage("export", self.export_vars());
+            stage("setup", self.traced_language(self.language.setup(&self.payload)));
+            stage("announce", self.traced_language(self.language.announce(&self.payload)));
         }
     }
 
     fn custom_stages_ast(&self) -> ast::Statement {
-        ast::Noop
+        let config = &self.payload.config;
+
+        ast_block! {
+            stage("before_install", stage_ast("before_install", &config.before_install, vec![ast::EchoOption, ast::AssertOption]));
+            stage("install", match config.install {
+                Some(ref cmds) => stage_ast("install", cmds, vec![ast::EchoOption, ast::AssertOption]),
+                None => self.traced_language(self.language.install(&self.payload)),
+            });
+            stage("before_script", stage_ast("before_script", &config.before_script, vec![ast::EchoOption, ast::AssertOption]));
+            stage("script", match config.script {
+                Some(ref cmds) => script_stage_ast(cmds),
+                None => self.traced_language(self.language.script(&self.payload)),
+            });
+            self.after_result_ast();
+            stage("after_script", stage_ast("after_script", &config.after_script, vec![ast::EchoOption]));
+        }
+    }
+
+    fn after_result_ast(&self) -> ast::Statement {
+        let config = &self.payload.config;
+        if config.after_success.is_empty() && config.after_failure.is_empty() {
+            return ast::Noop;
+        }
+
+        ast::If(ast::VarEquals("TRAVIS_TEST_RESULT".to_string(), "0".to_string()),
+                box stage("after_success", stage_ast("after_success", &config.after_success, vec![ast::EchoOption])),
+                box stage("after_failure", stage_ast("after_failure", &config.after_failure, vec![ast::EchoOption])))
     }
 
     fn apply_fixes(&self) -> ast::Statement {
         ast_block! {
             if self.payload.fix_resolv_conf {
-                format_cmd!("grep '199.91.168' /etc/resolv.conf > /dev/null || echo 'nameserver 199.91.168.70\nnameserver 199.91.168.71' | sudo tee /etc/resolv.conf &> /dev/null")
+                let grep = ast::SimpleCommand::new(&["grep", "199.91.168", "/etc/resolv.conf"])
+                    .redirect(ast::RedirectStdout(Path::new("/dev/null")));
+                let nameservers = ast::SimpleCommand::new(&["echo", "nameserver 199.91.168.70\nnameserver 199.91.168.71"]);
+                let tee = ast::SimpleCommand::new(&["sudo", "tee", "/etc/resolv.conf"])
+                    .redirect(ast::RedirectStdout(Path::new("/dev/null")))
+                    .redirect(ast::StderrToStdout);
+
+                ast_if!(!ast::CmdCond(ast::Exec(ast::Pipeline::new(vec![grep]))) {
+                    cmd!(ast::Exec(ast::Pipeline::new(vec![nameservers, tee])));
+                })
             } else {
                 ast::Noop
             };
@@ -72,12 +121,402 @@ impl Script {
         }
     }
 
+    /// Clears the colours the header defines, if the payload asks for messages without them.
+    fn disable_colors(&self) -> ast::Statement {
+        if !self.payload.no_color {
+            return ast::Noop;
+        }
+
+        no_color_ast()
+    }
+
+    /// Sets the variables `travis_retry` reads its policy from, so that every retried command
+    /// uses the policy of the payload unless it was given one of its own.
+    fn export_retry_policy(&self) -> ast::Statement {
+        let policy = &self.payload.retry;
+        let (backoff, delay, max_delay) = match policy.backoff {
+            ast::FixedBackoff(delay) => ("fixed", delay, delay),
+            ast::ExponentialBackoff(delay, max_delay) => ("exponential", delay, max_delay),
+        };
+
+        ast_block! {
+            ast_set!(TRAVIS_RETRY_ATTEMPTS = policy.attempts.to_string());
+            ast_set!(TRAVIS_RETRY_BACKOFF = backoff.to_string());
+            ast_set!(TRAVIS_RETRY_DELAY = delay.to_string());
+            ast_set!(TRAVIS_RETRY_MAX_DELAY = max_delay.to_string());
+        }
+    }
+
     fn export_vars(&self) -> ast::Statement {
         ast_block! {
             ast_set!(TRAVIS = "true".to_string());
             ast_set!(CI = "true".to_string());
             ast_set!(CONTINUOUS_INTEGRATION = "true".to_string());
             ast_set!(HAS_JOSH_K_SEAL_OF_APPROVAL = "true".to_string());
+            traced("components::env", components::env::export_env_ast(&self.payload));
         }
     }
+
+    /// Marks statements as coming from the language driver, like `languages::rust`.
+    fn traced_language(&self, statement: ast::Statement) -> ast::Statement {
+        traced(format!("languages::{}", self.payload.config.language).as_slice(), statement)
+    }
+}
+
+/// Renders an AST as a complete build script for the given shell.
+pub fn render(ast: &ast::Statement, shell: Shell) -> RenderResult<String> {
+    let (header, body, footer) = match shell {
+        Bash => (SCRIPT_HEADER, try!(ast.to_bash()), SCRIPT_FOOTER),
+        Posix => (POSIX_SCRIPT_HEADER, try!(ast.to_sh()), POSIX_SCRIPT_FOOTER),
+    };
+
+    let mut script = header.to_string();
+    script.push_str(body.as_slice());
+    script.push('\n');
+    script.push_str(footer);
+
+    Ok(script)
+}
+
+/// Renders an AST like `render`, and maps the lines of the script to the traced statements.
+pub fn render_with_source_map(ast: &ast::Statement, shell: Shell) -> RenderResult<(String, Vec<SourceMapping>)> {
+    let header = match shell {
+        Bash => SCRIPT_HEADER,
+        Posix => POSIX_SCRIPT_HEADER,
+    };
+    // The AST starts on the line after the header.
+    let first_line = header.chars().filter(|&c| c == '\n').count() + 1;
+    let script = try!(render(ast, shell));
+    Ok((script, try!(source_map(ast, first_line, shell))))
+}
+
+/// Empties the colour codes the helpers in the header print, so their messages are plain text.
+pub fn no_color_ast() -> ast::Statement {
+    ast_block! {
+        ast::Assign("ANSI_RED".to_string(), "".to_string());
+        ast::Assign("ANSI_GREEN".to_string(), "".to_string());
+        ast::Assign("ANSI_RESET".to_string(), "".to_string());
+        ast::Assign("ANSI_CLEAR".to_string(), "".to_string());
+    }
+}
+
+fn traced(source: &str, statement: ast::Statement) -> ast::Statement {
+    ast::Traced(source.to_string(), box statement)
+}
+
+/// Marks the statements as a stage of the build, so a command that fails in it is reported with
+/// the stage's name. Stages with nothing to run aren't marked.
+fn stage(name: &str, statement: ast::Statement) -> ast::Statement {
+    // Blocks of statements that do nothing, like `apply_fixes` without any fixes, only become
+    // `Noop` when they're normalized.
+    let statement = normalize(statement);
+    if statement.is_noop() {
+        return ast::Noop;
+    }
+
+    ast_block! {
+        ast::Stage(name.to_string());
+        statement;
+    }
+}
+
+/// Folds each command of a stage separately, numbering the folds if there's more than one command.
+/// Each command is traced back to its entry in the config, like `config.install[1]`.
+fn stage_ast(stage: &str, cmds: &Vec<String>, options: Vec<ast::CommandOption>) -> ast::Statement {
+    if cmds.is_empty() {
+        return ast::Noop;
+    }
+
+    let stmts = cmds.iter().enumerate().map(|(i, cmd)| {
+        let fold_name = if cmds.len() == 1 { stage.to_string() } else { format!("{}.{}", stage, i + 1) };
+        let fold = ast::Fold(fold_name, box ast::Cmd(ast::Raw(cmd.clone()), options.clone()));
+        traced(format!("config.{}[{}]", stage, i).as_slice(), fold)
+    }).collect();
+
+    ast::Statements(box stmts)
+}
+
+/// The script stage isn't folded, and a failing command doesn't stop the build, it only marks it as failed.
+fn script_stage_ast(cmds: &Vec<String>) -> ast::Statement {
+    if cmds.is_empty() {
+        return ast::Noop;
+    }
+
+    let stmts = cmds.iter().enumerate().map(|(i, cmd)| {
+        traced(format!("config.script[{}]", i).as_slice(), cmd!([EchoOption|ResultOption], ast::Raw(cmd.clone())))
+    }).collect();
+
+    ast::Statements(box stmts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Script, render, render_with_source_map};
+    use payload::{Bash,Posix};
+    use ast;
+    use ast::normalize::normalize;
+    use bash::ToBash;
+    use test_ast_runner::TestAstRunner;
+    use payload::test::a_payload;
+
+    #[test]
+    fn test_no_custom_stages() {
+        let script = Script::new(a_payload());
+
+        assert_eq!("", script.custom_stages_ast().to_bash().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_stage_order() {
+        let mut payload = a_payload();
+        payload.config.after_script = vec!["./after_script".to_string()];
+        payload.config.script = Some(vec!["./script".to_string()]);
+        payload.config.before_script = vec!["./before_script".to_string()];
+        payload.config.install = Some(vec!["./install".to_string()]);
+        payload.config.before_install = vec!["./before_install".to_string()];
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Script::new(payload).custom_stages_ast());
+
+        let commands: Vec<&str> = runner.commands.iter().map(|&(ref command, _)| command.as_slice()).collect();
+        assert_eq!(vec!["./before_install", "./install", "./before_script", "./script", "./after_script"], commands);
+    }
+
+    #[test]
+    fn test_setup_stages_are_folded_and_asserted() {
+        let mut payload = a_payload();
+        payload.config.before_install = vec!["./one".to_string(), "./two".to_string()];
+        payload.config.install = Some(vec!["./three".to_string()]);
+
+        let bash = Script::new(payload).custom_stages_ast().to_bash().unwrap();
+
+        assert!(bash.as_slice().contains("travis_fold start before_install.1\ntravis_cmd ./one --echo --assert\ntravis_fold end before_install.1"));
+        assert!(bash.as_slice().contains("travis_fold start before_install.2\ntravis_cmd ./two --echo --assert\ntravis_fold end before_install.2"));
+        assert!(bash.as_slice().contains("travis_fold start install\ntravis_cmd ./three --echo --assert\ntravis_fold end install"));
+    }
+
+    #[test]
+    fn test_script_reports_result() {
+        let mut payload = a_payload();
+        payload.config.script = Some(vec!["make test".to_string()]);
+
+        let bash = Script::new(payload).custom_stages_ast().to_bash().unwrap();
+
+        assert_eq!("export TRAVIS_STAGE=script\ntravis_cmd 'make test' --echo --result", bash.as_slice());
+    }
+
+    #[test]
+    fn test_after_success_and_failure() {
+        let mut payload = a_payload();
+        payload.config.after_success = vec!["./deploy".to_string()];
+        payload.config.after_failure = vec!["cat log".to_string()];
+
+        let bash = Script::new(payload).custom_stages_ast().to_bash().unwrap();
+
+        assert_eq!("if [[ $TRAVIS_TEST_RESULT = 0 ]]; then\n  export TRAVIS_STAGE=after_success\n  travis_fold start after_success\n  travis_cmd ./deploy --echo\n  travis_fold end after_success\nelse\n  export TRAVIS_STAGE=after_failure\n  travis_fold start after_failure\n  travis_cmd 'cat log' --echo\n  travis_fold end after_failure\nfi", bash.as_slice());
+    }
+
+    #[test]
+    fn test_stages_are_marked() {
+        let mut payload = a_payload();
+        payload.config.install = Some(vec!["./install".to_string()]);
+        payload.config.script = Some(vec!["make test".to_string()]);
+        payload.config.after_script = vec!["./after_script".to_string()];
+
+        let bash = Script::new(payload).to_ast().to_bash().unwrap();
+        let stages: Vec<&str> = bash.as_slice().lines().filter_map(|line| {
+            let line = line.trim();
+            if line.starts_with("export TRAVIS_STAGE=") { Some(line.slice_from(20)) } else { None }
+        }).collect();
+
+        assert_eq!(vec!["checkout", "export", "install", "script", "after_script"], stages);
+        assert!(bash.as_slice().contains("export TRAVIS_STAGE=install\ntravis_fold start install\n"));
+    }
+
+    #[test]
+    fn test_stage_follows_the_test_result() {
+        let mut payload = a_payload();
+        payload.config.script = Some(vec!["make test".to_string()]);
+        payload.config.after_success = vec!["./deploy".to_string()];
+        payload.config.after_failure = vec!["cat log".to_string()];
+
+        let mut runner = TestAstRunner::new();
+        runner.command_results.insert("make test".to_string(), 1);
+        runner.run(&Script::new(payload).custom_stages_ast());
+
+        assert_eq!(Some(&"after_failure".to_string()), runner.environment_vars.find(&"TRAVIS_STAGE".to_string()));
+    }
+
+    #[test]
+    fn test_no_color() {
+        let mut payload = a_payload();
+        payload.no_color = true;
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Script::new(payload).to_ast());
+
+        assert_eq!(Some(&"".to_string()), runner.shell_vars.find(&"ANSI_RED".to_string()));
+        assert_eq!(Some(&"".to_string()), runner.shell_vars.find(&"ANSI_CLEAR".to_string()));
+        assert!(Script::new(a_payload()).disable_colors().is_noop());
+    }
+
+    #[test]
+    fn test_header_defines_colors_and_result() {
+        for &shell in [Bash, Posix].iter() {
+            let script = render(&ast::Noop, shell).unwrap();
+
+            for var in ["ANSI_RED", "ANSI_GREEN", "ANSI_RESET", "ANSI_CLEAR"].iter() {
+                assert!(script.as_slice().contains(format!("\n{}=\"\\033[", var).as_slice()), "{} isn't defined for {}", var, shell);
+            }
+            assert!(script.as_slice().contains("\nexport TRAVIS_TEST_RESULT=0\n"));
+        }
+    }
+
+    #[test]
+    fn test_fix_resolv_conf() {
+        let mut payload = a_payload();
+        payload.fix_resolv_conf = true;
+
+        let bash = Script::new(payload).apply_fixes().to_bash().unwrap();
+
+        assert_eq!("if { ! grep 199.91.168 /etc/resolv.conf > /dev/null; }; then\n  travis_cmd 'echo $'\\''nameserver 199.91.168.70\\nnameserver 199.91.168.71'\\'' | sudo tee /etc/resolv.conf > /dev/null 2>&1'\nfi", bash.as_slice());
+    }
+
+    #[test]
+    fn test_retry_policy_is_exported() {
+        let mut payload = a_payload();
+        payload.retry = ast::RetryPolicy { attempts: 5, backoff: ast::ExponentialBackoff(2, 30) };
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Script::new(payload).export_retry_policy());
+
+        assert_eq!(Some(&"5".to_string()), runner.environment_vars.find(&"TRAVIS_RETRY_ATTEMPTS".to_string()));
+        assert_eq!(Some(&"exponential".to_string()), runner.environment_vars.find(&"TRAVIS_RETRY_BACKOFF".to_string()));
+        assert_eq!(Some(&"2".to_string()), runner.environment_vars.find(&"TRAVIS_RETRY_DELAY".to_string()));
+        assert_eq!(Some(&"30".to_string()), runner.environment_vars.find(&"TRAVIS_RETRY_MAX_DELAY".to_string()));
+    }
+
+    #[test]
+    fn test_after_failure_runs_when_script_fails() {
+        let mut payload = a_payload();
+        payload.config.script = Some(vec!["make test".to_string(), "make lint".to_string()]);
+        payload.config.after_success = vec!["./deploy".to_string()];
+        payload.config.after_failure = vec!["cat log".to_string()];
+
+        let mut runner = TestAstRunner::new();
+        runner.command_results.insert("make test".to_string(), 1);
+        runner.run(&Script::new(payload).custom_stages_ast());
+
+        let commands: Vec<&str> = runner.commands.iter().map(|&(ref command, _)| command.as_slice()).collect();
+        assert_eq!(vec!["make test", "make lint", "cat log"], commands);
+        assert_eq!(Some(&"1".to_string()), runner.environment_vars.find(&"TRAVIS_TEST_RESULT".to_string()));
+    }
+
+    #[test]
+    fn test_after_success_runs_when_script_passes() {
+        let mut payload = a_payload();
+        payload.config.script = Some(vec!["make test".to_string()]);
+        payload.config.after_success = vec!["./deploy".to_string()];
+        payload.config.after_failure = vec!["cat log".to_string()];
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Script::new(payload).custom_stages_ast());
+
+        let commands: Vec<&str> = runner.commands.iter().map(|&(ref command, _)| command.as_slice()).collect();
+        assert_eq!(vec!["make test", "./deploy"], commands);
+    }
+
+    #[test]
+    fn test_normalized_script_is_equivalent() {
+        let mut payload = a_payload();
+        payload.config.before_install = vec!["./one".to_string(), "./two".to_string()];
+        payload.config.script = Some(vec!["make test".to_string()]);
+        payload.config.after_success = vec!["./deploy".to_string()];
+
+        let ast = Script::new(payload).generate_ast();
+        let mut original = TestAstRunner::new();
+        original.run(&ast);
+        let mut normalized = TestAstRunner::new();
+        normalized.run(&normalize(ast));
+
+        assert_eq!(original.commands, normalized.commands);
+        assert_eq!(original.environment_vars, normalized.environment_vars);
+    }
+
+    #[test]
+    fn test_source_map_points_at_config_entries() {
+        let mut payload = a_payload();
+        payload.config.install = Some(vec!["./install".to_string()]);
+        payload.config.script = Some(vec!["make test".to_string(), "make lint".to_string()]);
+
+        let (script, mappings) = render_with_source_map(&Script::new(payload).to_ast(), Bash).unwrap();
+        let lines: Vec<&str> = script.as_slice().split('\n').collect();
+        let line_of = |source: &str| {
+            let mapping = mappings.iter().find(|mapping| mapping.source.as_slice() == source).unwrap();
+            lines.slice(mapping.first_line - 1, mapping.last_line).connect("\n")
+        };
+
+        assert_eq!("travis_fold start install\ntravis_cmd ./install --echo --assert\ntravis_fold end install", line_of("config.install[0]").as_slice());
+        assert_eq!("travis_cmd 'make lint' --echo --result", line_of("config.script[1]").as_slice());
+        assert!(line_of("components::git").as_slice().contains("travis_cmd 'git clone"));
+    }
+
+    #[test]
+    fn test_posix_script() {
+        let mut payload = a_payload();
+        payload.shell = Posix;
+        payload.config.script = Some(vec!["make test".to_string()]);
+
+        let script = Script::new(payload).to_script().unwrap();
+
+        assert!(script.as_slice().starts_with("#!/bin/sh\n"));
+        assert!(!script.as_slice().contains("[["));
+        assert!(script.as_slice().contains("\ntravis_cmd 'make test' --echo --result\n"));
+    }
+
+    #[test]
+    fn test_source_map_for_posix_script() {
+        let mut payload = a_payload();
+        payload.config.script = Some(vec!["make test".to_string()]);
+
+        let (script, mappings) = render_with_source_map(&Script::new(payload).to_ast(), Posix).unwrap();
+        let lines: Vec<&str> = script.as_slice().split('\n').collect();
+        let mapping = mappings.iter().find(|mapping| mapping.source.as_slice() == "config.script[0]").unwrap();
+
+        assert_eq!("travis_cmd 'make test' --echo --result", lines[mapping.first_line - 1]);
+    }
+
+    #[test]
+    fn test_unrenderable_command() {
+        let mut payload = a_payload();
+        payload.config.script = Some(vec!["make".to_string(), "echo \x00".to_string()]);
+
+        let error = Script::new(payload).to_script().unwrap_err();
+        assert_eq!(vec!["config.script[1]".to_string()], error.context);
+    }
+
+    #[test]
+    fn test_rust_defaults() {
+        let mut payload = a_payload();
+        payload.config.language = "rust".to_string();
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Script::new(payload).custom_stages_ast());
+
+        let commands: Vec<&str> = runner.commands.iter().map(|&(ref command, _)| command.as_slice()).collect();
+        assert_eq!(vec!["cargo fetch", "cargo build --verbose && cargo test --verbose"], commands);
+    }
+
+    #[test]
+    fn test_rust_custom_script() {
+        let mut payload = a_payload();
+        payload.config.language = "rust".to_string();
+        payload.config.script = Some(vec!["make check".to_string()]);
+
+        let mut runner = TestAstRunner::new();
+        runner.run(&Script::new(payload).custom_stages_ast());
+
+        let commands: Vec<&str> = runner.commands.iter().map(|&(ref command, _)| command.as_slice()).collect();
+        assert_eq!(vec!["cargo fetch", "make check"], commands);
+    }
 }
@@ -1,10 +1,16 @@
 pub static SCRIPT_HEADER: &'static str = "#!/bin/bash
 
+ANSI_RED=\"\\033[31;1m\"
+ANSI_GREEN=\"\\033[32;1m\"
+ANSI_RESET=\"\\033[0m\"
+ANSI_CLEAR=\"\\033[0K\"
+
+export TRAVIS_TEST_RESULT=0
+
 function travis_cmd() {
-    local assert output display retry timing cmd result
+    local assert output display retry timing report cmd result TRAVIS_CMD
 
     cmd=$1
-    TRAVIS_CMD=$cmd
     shift
 
     while true; do
@@ -12,21 +18,31 @@ function travis_cmd() {
             --assert)  assert=true; shift ;;
             --echo)    output=true; shift ;;
             --display) display=$2;  shift 2;;
+            --display=*) display=${1#--display=}; shift ;;
             --retry)   retry=true;  shift ;;
+            --retry-attempts=*)  local TRAVIS_RETRY_ATTEMPTS=${1#--retry-attempts=}; shift ;;
+            --retry-backoff=*)   local TRAVIS_RETRY_BACKOFF=${1#--retry-backoff=}; shift ;;
+            --retry-delay=*)     local TRAVIS_RETRY_DELAY=${1#--retry-delay=}; shift ;;
+            --retry-max-delay=*) local TRAVIS_RETRY_MAX_DELAY=${1#--retry-max-delay=}; shift ;;
             --timing)  timing=true; shift ;;
+            --result)  report=true; shift ;;
             *) break ;;
         esac
     done
 
+    # The messages about the command show what was echoed, which leaves out secrets.
+    TRAVIS_CMD=${display:-$cmd}
+
     if [[ -n \"$timing\" ]]; then
         travis_time_start
     fi
 
     if [[ -n \"$output\" ]]; then
-        echo \"\\$ ${display:-$cmd}\"
+        echo \"\\$ $TRAVIS_CMD\"
     fi
 
     if [[ -n \"$retry\" ]]; then
+        local TRAVIS_RETRY_DISPLAY=$TRAVIS_CMD
         travis_retry eval \"$cmd\"
     else
         eval \"$cmd\"
@@ -41,6 +57,10 @@ function travis_cmd() {
         travis_assert $result
     fi
 
+    if [[ -n \"$report\" ]]; then
+        travis_result $result
+    fi
+
     return $result
 }
 
@@ -145,18 +165,27 @@ travis_jigger() {
 travis_retry() {
     local result=0
     local count=1
-    while [ $count -le 3 ]; do
+    local attempts=${TRAVIS_RETRY_ATTEMPTS:-3}
+    local delay=${TRAVIS_RETRY_DELAY:-1}
+    local max_delay=${TRAVIS_RETRY_MAX_DELAY:-60}
+    while [ $count -le $attempts ]; do
         [ $result -ne 0 ] && {
-            echo -e \"\\n${ANSI_RED}The command \\\"$@\\\" failed. Retrying, $count of 3.${ANSI_RESET}\\n\" >&2
+            echo -e \"\\n${ANSI_RED}The command \\\"${TRAVIS_RETRY_DISPLAY:-$*}\\\" failed. Retrying, $count of $attempts.${ANSI_RESET}\\n\" >&2
         }
         \"$@\"
         result=$?
         [ $result -eq 0 ] && break
         count=$(($count + 1))
-        sleep 1
+        if [ $count -le $attempts ]; then
+            sleep $delay
+            if [ \"${TRAVIS_RETRY_BACKOFF:-fixed}\" = exponential ]; then
+                delay=$(($delay * 2))
+                [ $delay -gt $max_delay ] && delay=$max_delay
+            fi
+        fi
     done
-    [ $count -gt 3 ] && {
-        echo -e \"\\n${ANSI_RED}The command \\\"$@\\\" failed 3 times.${ANSI_RESET}\\n\" >&2
+    [ $count -gt $attempts ] && {
+        echo -e \"\\n${ANSI_RED}The command \\\"${TRAVIS_RETRY_DISPLAY:-$*}\\\" failed $attempts times.${ANSI_RESET}\\n\" >&2
     }
     return $result
 }
@@ -170,3 +199,203 @@ travis_fold() {
 ";
 
 pub static SCRIPT_FOOTER: &'static str = "\necho -e \"\\nDone. Your build exited with $TRAVIS_TEST_RESULT.\"\n\ntravis_terminate $TRAVIS_TEST_RESULT\n";
+
+/// The helpers of `SCRIPT_HEADER` for shells that only support POSIX sh, like dash or busybox.
+///
+/// `local` isn't POSIX, but dash, busybox and the other shells on the images support it, so
+/// `travis_cmd` and `travis_retry` keep their state in local variables, which a nested `travis_cmd`
+/// can't overwrite. The other helpers keep their state in global variables that start with their
+/// name.
+pub static POSIX_SCRIPT_HEADER: &'static str = "#!/bin/sh
+
+ANSI_RED=\"\\033[31;1m\"
+ANSI_GREEN=\"\\033[32;1m\"
+ANSI_RESET=\"\\033[0m\"
+ANSI_CLEAR=\"\\033[0K\"
+
+export TRAVIS_TEST_RESULT=0
+
+travis_cmd() {
+    # A local without a value keeps the value of the caller in dash, so every one is assigned.
+    local travis_cmd_assert= travis_cmd_output= travis_cmd_display= travis_cmd_retry= travis_cmd_timing= travis_cmd_report=
+    local travis_cmd_retry_attempts= travis_cmd_retry_backoff= travis_cmd_retry_delay= travis_cmd_retry_max_delay=
+    local travis_cmd_retry_display= travis_cmd_result= TRAVIS_CMD=
+
+    local travis_cmd_cmd=$1
+    shift
+
+    while true; do
+        case \"$1\" in
+            --assert)  travis_cmd_assert=true; shift ;;
+            --echo)    travis_cmd_output=true; shift ;;
+            --display) travis_cmd_display=$2; shift 2 ;;
+            --display=*) travis_cmd_display=${1#--display=}; shift ;;
+            --retry)   travis_cmd_retry=true; shift ;;
+            --retry-attempts=*)  travis_cmd_retry_attempts=${1#--retry-attempts=}; shift ;;
+            --retry-backoff=*)   travis_cmd_retry_backoff=${1#--retry-backoff=}; shift ;;
+            --retry-delay=*)     travis_cmd_retry_delay=${1#--retry-delay=}; shift ;;
+            --retry-max-delay=*) travis_cmd_retry_max_delay=${1#--retry-max-delay=}; shift ;;
+            --timing)  travis_cmd_timing=true; shift ;;
+            --result)  travis_cmd_report=true; shift ;;
+            *) break ;;
+        esac
+    done
+
+    # The messages about the command show what was echoed, which leaves out secrets.
+    TRAVIS_CMD=${travis_cmd_display:-$travis_cmd_cmd}
+
+    if [ -n \"$travis_cmd_timing\" ]; then
+        travis_time_start
+    fi
+
+    if [ -n \"$travis_cmd_output\" ]; then
+        printf '$ %s\\n' \"$TRAVIS_CMD\"
+    fi
+
+    if [ -n \"$travis_cmd_retry\" ]; then
+        travis_cmd_retry_display=$TRAVIS_CMD
+        travis_retry eval \"$travis_cmd_cmd\"
+        travis_cmd_result=$?
+    else
+        eval \"$travis_cmd_cmd\"
+        travis_cmd_result=$?
+    fi
+
+    if [ -n \"$travis_cmd_timing\" ]; then
+        travis_time_finish
+    fi
+
+    if [ -n \"$travis_cmd_assert\" ]; then
+        travis_assert $travis_cmd_result
+    fi
+
+    if [ -n \"$travis_cmd_report\" ]; then
+        travis_result $travis_cmd_result
+    fi
+
+    return $travis_cmd_result
+}
+
+travis_time_start() {
+    travis_timer_id=$(od -An -N4 -tx4 /dev/urandom | tr -d ' ')
+    travis_start_time=$(travis_nanoseconds)
+    printf 'travis_time:start:%s\\r%b' \"$travis_timer_id\" \"$ANSI_CLEAR\"
+}
+
+travis_time_finish() {
+    travis_end_time=$(travis_nanoseconds)
+    travis_duration=$(($travis_end_time - $travis_start_time))
+    printf 'travis_time:end:%s:start=%s,finish=%s,duration=%s\\r%b' \"$travis_timer_id\" \"$travis_start_time\" \"$travis_end_time\" \"$travis_duration\" \"$ANSI_CLEAR\"
+}
+
+travis_nanoseconds() {
+    travis_nanoseconds=$(date -u +%s%N)
+    case \"$travis_nanoseconds\" in
+        # %N isn't POSIX, fall back to second precision if date doesn't support it
+        *N) travis_nanoseconds=\"$(date -u +%s)000000000\" ;;
+    esac
+    echo \"$travis_nanoseconds\"
+}
+
+travis_assert() {
+    travis_assert_result=${1:-$?}
+    if [ $travis_assert_result -ne 0 ]; then
+        printf '\\n%bThe command \"%s\" failed and exited with %s during %s.%b\\n\\nYour build has been stopped.\\n' \"$ANSI_RED\" \"$TRAVIS_CMD\" \"$travis_assert_result\" \"$TRAVIS_STAGE\" \"$ANSI_RESET\"
+        travis_terminate 2
+    fi
+}
+
+travis_result() {
+    travis_result_code=$1
+    export TRAVIS_TEST_RESULT=$(( ${TRAVIS_TEST_RESULT:-0} | ($travis_result_code != 0) ))
+    if [ $travis_result_code -eq 0 ]; then
+        printf '\\n%bThe command \"%s\" exited with %s.%b\\n' \"$ANSI_GREEN\" \"$TRAVIS_CMD\" \"$travis_result_code\" \"$ANSI_RESET\"
+    else
+        printf '\\n%bThe command \"%s\" exited with %s.%b\\n' \"$ANSI_RED\" \"$TRAVIS_CMD\" \"$travis_result_code\" \"$ANSI_RESET\"
+    fi
+}
+
+travis_terminate() {
+    pkill -9 -P $$ > /dev/null 2>&1 || true
+    exit $1
+}
+
+travis_wait() {
+    travis_wait_timeout=20
+    case \"$1\" in
+        ''|*[!0-9]*) ;;
+        # looks like an integer, so we assume it's a timeout
+        *) travis_wait_timeout=$1; shift ;;
+    esac
+    travis_wait_log=travis_wait_$$.log
+    \"$@\" > $travis_wait_log 2>&1 &
+    travis_wait_pid=$!
+    travis_jigger $travis_wait_pid $travis_wait_timeout \"$@\" &
+    travis_wait_jigger_pid=$!
+    wait $travis_wait_pid 2> /dev/null
+    travis_wait_result=$?
+    kill $travis_wait_jigger_pid > /dev/null 2>&1
+    if [ $travis_wait_result -eq 0 ]; then
+        printf '\\n%bThe command \"%s\" exited with %s.%b\\n' \"$ANSI_GREEN\" \"$TRAVIS_CMD\" \"$travis_wait_result\" \"$ANSI_RESET\"
+    else
+        printf '\\n%bThe command \"%s\" exited with %s.%b\\n' \"$ANSI_RED\" \"$TRAVIS_CMD\" \"$travis_wait_result\" \"$ANSI_RESET\"
+    fi
+    printf '\\n%bLog:%b\\n\\n' \"$ANSI_GREEN\" \"$ANSI_RESET\"
+    cat $travis_wait_log
+    return $travis_wait_result
+}
+
+travis_jigger() {
+    # helper method for travis_wait()
+    travis_jigger_pid=$1
+    shift
+    travis_jigger_timeout=$1 # in minutes
+    shift
+    travis_jigger_count=0
+    # clear the line
+    printf '\\n\\n'
+    while [ $travis_jigger_count -lt $travis_jigger_timeout ]; do
+        travis_jigger_count=$(($travis_jigger_count + 1))
+        printf 'Still running (%s of %s): %s\\r' \"$travis_jigger_count\" \"$travis_jigger_timeout\" \"$*\"
+        sleep 60
+    done
+    printf '\\n%bTimeout (%s minutes) reached. Terminating \"%s\"%b\\n\\n' \"$ANSI_RED\" \"$travis_jigger_timeout\" \"$*\" \"$ANSI_RESET\"
+    kill -9 $travis_jigger_pid
+}
+
+travis_retry() {
+    local travis_retry_result=0
+    local travis_retry_count=1
+    local travis_retry_attempts=${travis_cmd_retry_attempts:-${TRAVIS_RETRY_ATTEMPTS:-3}}
+    local travis_retry_backoff=${travis_cmd_retry_backoff:-${TRAVIS_RETRY_BACKOFF:-fixed}}
+    local travis_retry_delay=${travis_cmd_retry_delay:-${TRAVIS_RETRY_DELAY:-1}}
+    local travis_retry_max_delay=${travis_cmd_retry_max_delay:-${TRAVIS_RETRY_MAX_DELAY:-60}}
+    while [ $travis_retry_count -le $travis_retry_attempts ]; do
+        [ $travis_retry_result -ne 0 ] && {
+            printf '\\n%bThe command \"%s\" failed. Retrying, %s of %s.%b\\n\\n' \"$ANSI_RED\" \"${travis_cmd_retry_display:-$*}\" \"$travis_retry_count\" \"$travis_retry_attempts\" \"$ANSI_RESET\" >&2
+        }
+        \"$@\"
+        travis_retry_result=$?
+        [ $travis_retry_result -eq 0 ] && break
+        travis_retry_count=$(($travis_retry_count + 1))
+        if [ $travis_retry_count -le $travis_retry_attempts ]; then
+            sleep $travis_retry_delay
+            if [ \"$travis_retry_backoff\" = exponential ]; then
+                travis_retry_delay=$(($travis_retry_delay * 2))
+                [ $travis_retry_delay -gt $travis_retry_max_delay ] && travis_retry_delay=$travis_retry_max_delay
+            fi
+        fi
+    done
+    [ $travis_retry_count -gt $travis_retry_attempts ] && {
+        printf '\\n%bThe command \"%s\" failed %s times.%b\\n\\n' \"$ANSI_RED\" \"${travis_cmd_retry_display:-$*}\" \"$travis_retry_attempts\" \"$ANSI_RESET\" >&2
+    }
+    return $travis_retry_result
+}
+
+travis_fold() {
+    printf 'travis_fold:%s:%s\\r%b' \"$1\" \"$2\" \"$ANSI_CLEAR\"
+}
+
+";
+
+pub static POSIX_SCRIPT_FOOTER: &'static str = "\nprintf '\\nDone. Your build exited with %s.\\n' \"$TRAVIS_TEST_RESULT\"\n\ntravis_terminate $TRAVIS_TEST_RESULT\n";
@@ -0,0 +1,256 @@
+//! Renders the AST as POSIX shell, for images that only ship a minimal `sh` like dash or busybox.
+//!
+//! Statements go through the same walk as bash, `bash::render`, with POSIX quoting and
+//! conditions, so they're laid out on the same lines and the source map can follow both.
+//!
+//! Besides `sh`, the image needs a `base64` that decodes with `-d`, since `ast::Putfile` writes
+//! files with it. POSIX doesn't specify `base64`, but GNU coreutils and busybox both have one.
+
+use ast;
+use bash::{Dialect, RenderResult, double_quote, quote_error, render, render_command};
+use quote::quote_posix;
+
+fn escape(input: &str) -> RenderResult<String> {
+    escape_bytes(input.as_bytes())
+}
+
+/// Quotes a path, which doesn't have to be UTF-8.
+pub fn escape_path(path: &Path) -> RenderResult<String> {
+    escape_bytes(path.as_vec())
+}
+
+fn escape_bytes(input: &[u8]) -> RenderResult<String> {
+    quote_posix(input).map_err(|e| quote_error(input, e))
+}
+
+/// Busybox's `base64` doesn't know `--decode`, so files are decoded with `-d`.
+static POSIX: Dialect = Dialect {
+    escape: escape,
+    escape_path: escape_path,
+    condition: condition,
+    decode_base64: "base64 -d",
+};
+
+fn condition(condition: &ast::Condition) -> RenderResult<String> {
+    condition.to_sh()
+}
+
+/// Renders the AST as POSIX shell. Like `ToBash`, rendering fails if a word has a NUL byte.
+pub trait ToSh {
+    fn to_sh(&self) -> RenderResult<String>;
+}
+
+impl ToSh for ast::Statement {
+    fn to_sh(&self) -> RenderResult<String> {
+        render(self, &POSIX)
+    }
+}
+
+impl ToSh for ast::Command {
+    fn to_sh(&self) -> RenderResult<String> {
+        render_command(self, &POSIX)
+    }
+}
+
+impl ToSh for ast::Condition {
+    fn to_sh(&self) -> RenderResult<String> {
+        Ok(match *self {
+            ast::Exists(ref path) => format!("[ -e {} ]", try!(escape_path(path))),
+            ast::IsDirectory(ref path) => format!("[ -d {} ]", try!(escape_path(path))),
+            ast::IsFile(ref path) => format!("[ -f {} ]", try!(escape_path(path))),
+            ast::CmdCond(ref command) => try!(command.to_sh()),
+            ast::IsSet(ref var) => format!("[ -n \"${{{}+x}}\" ]", var),
+            ast::VarEquals(ref var, ref value) => format!("[ \"${}\" = {} ]", var, try!(escape(value.as_slice()))),
+            ast::StrEquals(ref left, ref right) => format!("[ {} = {} ]", try!(double_quote(left.as_slice())), try!(double_quote(right.as_slice()))),
+            // There's no `=~`, so grep does the matching. Unlike bash, it matches each line of the
+            // string on its own.
+            ast::Matches(ref string, ref regex) => format!("printf '%s\\n' {} | grep -E -q -e {}", try!(double_quote(string.as_slice())), try!(escape(regex.as_slice()))),
+            ast::CommandAvailable(ref command) => format!("command -v {} > /dev/null 2>&1", try!(escape(command.as_slice()))),
+            ast::LastSucceeded => "[ $? -eq 0 ]".to_string(),
+            ast::And(ref cond1, ref cond2) => format!("{{ {} && {}; }}", try!(cond1.to_sh()), try!(cond2.to_sh())),
+            ast::Or(ref cond1, ref cond2) => format!("{{ {} || {}; }}", try!(cond1.to_sh()), try!(cond2.to_sh())),
+            ast::Not(ref condition) => format!("{{ ! {}; }}", try!(condition.to_sh())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ToSh;
+    use ast;
+    use ast::fixtures;
+    use bash::{ToBash, open_quote};
+    use script_templates::{SCRIPT_HEADER,SCRIPT_FOOTER,POSIX_SCRIPT_HEADER,POSIX_SCRIPT_FOOTER};
+    use std::io::process::{Command,ProcessExit,ExitStatus};
+
+    /// Checks the syntax of a script with `shell -n`, without running it.
+    fn assert_parses(shell: &str, script: &str) {
+        let output = match Command::new(shell).arg("-n").arg("-c").arg(script).output() {
+            Ok(output) => output,
+            Err(e) => panic!("couldn't run {}: {}", shell, e),
+        };
+        if !output.status.success() {
+            panic!("{} couldn't parse:\n{}\n{}", shell, script, String::from_utf8_lossy(output.error.as_slice()));
+        }
+    }
+
+    /// Runs the statement with the header and footer of the shell, and returns how it exited and
+    /// everything it printed.
+    fn run(shell: &str, statement: &ast::Statement) -> (ProcessExit, String) {
+        let script = match shell {
+            "bash" => format!("{}{}\n{}", SCRIPT_HEADER, statement.to_bash().unwrap(), SCRIPT_FOOTER),
+            _ => format!("{}{}\n{}", POSIX_SCRIPT_HEADER, statement.to_sh().unwrap(), POSIX_SCRIPT_FOOTER),
+        };
+        let output = match Command::new(shell).arg("-c").arg(script.as_slice()).output() {
+            Ok(output) => output,
+            Err(e) => panic!("couldn't run {}: {}", shell, e),
+        };
+        (output.status, format!("{}{}", String::from_utf8_lossy(output.output.as_slice()), String::from_utf8_lossy(output.error.as_slice())))
+    }
+
+    #[test]
+    fn test_statement_to_sh() {
+        assert_eq!("travis_cmd 'make test' --echo --result", format_cmd!([EchoOption|ResultOption], "make test").to_sh().unwrap().as_slice());
+        assert_eq!("if [ -f Makefile ]; then\n  travis_cmd make\nelif [ -f configure ]; then\n  travis_cmd ./configure\nelse\n  :\nfi",
+                   ast_if!(ast::IsFile(Path::new("Makefile")) { format_cmd!("make"); } else { ast_if!(ast::IsFile(Path::new("configure")) { format_cmd!("./configure"); } else { ast::Noop; }); }).to_sh().unwrap().as_slice());
+        assert_eq!("greet() {\n  local name=\"${1}\"\n  local message=hello\n}", ast::Function("greet".to_string(), vec!["name".to_string()], box ast::Local("message".to_string(), "hello".to_string())).to_sh().unwrap().as_slice());
+        assert_eq!("export TRAVIS_STAGE=after_script", ast::Stage("after_script".to_string()).to_sh().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_command_to_sh() {
+        assert_eq!("base64 -d > file <<'TRAVIS_PUTFILE'\nAGJpbmFyef8=\nTRAVIS_PUTFILE", ast::Putfile(Path::new("file"), b"\x00binary\xff".to_vec(), ast::PutfileOptions::default()).to_sh().unwrap().as_slice());
+        assert_eq!("travis_cmd 'base64 -d | gunzip >> file' <<'TRAVIS_PUTFILE'", cmd!(ast::Putfile(Path::new("file"), vec![], ast::PutfileOptions::default().compress().append())).to_sh().unwrap().as_slice().lines().next().unwrap());
+        assert!(cmd!([RetryOption], ast::Putfile(Path::new("file"), vec![], ast::PutfileOptions::default())).to_sh().is_err());
+        assert_eq!("mkdir -p dir", ast::Mkdir(Path::new("dir")).to_sh().unwrap().as_slice());
+        assert_eq!("cd 'caf'\"$(printf '\\303')\"", ast::Cd(Path::new(b"caf\xc3")).to_sh().unwrap().as_slice());
+        assert_eq!("export MESSAGE='one\ntwo'", ast::Envset("MESSAGE".to_string(), "one\ntwo".to_string()).to_sh().unwrap().as_slice());
+        assert_eq!("grep 'it'\\''s' < 'in put' | tee out 2>&1 &", ast::Exec(ast::Pipeline::new(vec![
+            ast::SimpleCommand::new(&["grep", "it's"]).redirect(ast::RedirectStdin(Path::new("in put"))),
+            ast::SimpleCommand::new(&["tee", "out"]).redirect(ast::StderrToStdout),
+        ]).background()).to_sh().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_condition_to_sh() {
+        assert_eq!("[ -e file ]", ast::Exists(Path::new("file")).to_sh().unwrap().as_slice());
+        assert_eq!("[ -n \"${CI+x}\" ]", ast::IsSet("CI".to_string()).to_sh().unwrap().as_slice());
+        assert_eq!("[ \"$RUST\" = 'nightly 2014' ]", ast::VarEquals("RUST".to_string(), "nightly 2014".to_string()).to_sh().unwrap().as_slice());
+        assert_eq!("[ \"$A\" = \"\\\"b\\\"\" ]", ast::StrEquals("$A".to_string(), "\"b\"".to_string()).to_sh().unwrap().as_slice());
+        assert_eq!("printf '%s\\n' \"$TRAVIS_TAG\" | grep -E -q -e '^v[0-9]+'", ast::Matches("$TRAVIS_TAG".to_string(), "^v[0-9]+".to_string()).to_sh().unwrap().as_slice());
+        assert_eq!("command -v rustc > /dev/null 2>&1", ast::CommandAvailable("rustc".to_string()).to_sh().unwrap().as_slice());
+        assert_eq!("[ $? -eq 0 ]", ast::LastSucceeded.to_sh().unwrap().as_slice());
+        assert_eq!("{ ! { [ -d a ] && [ -f b ]; }; }", ast::Not(box ast::And(box ast::IsDirectory(Path::new("a")), box ast::IsFile(Path::new("b")))).to_sh().unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_nul_bytes_cant_be_rendered() {
+        assert!(ast::Envset("FOO".to_string(), "\x00".to_string()).to_sh().is_err());
+        let error = ast::Traced("config.install[0]".to_string(), box format_cmd!("echo \x00")).to_sh().unwrap_err();
+        assert_eq!(vec!["config.install[0]".to_string()], error.context);
+    }
+
+    /// Counts the lines that don't continue a quoted word. Bash writes newlines in words as `\n`,
+    /// but sh keeps them in single quotes.
+    fn count_lines(script: &str) -> uint {
+        let mut quote = None;
+        let mut count = 0;
+        for line in script.lines() {
+            if quote.is_none() {
+                count += 1;
+            }
+            quote = open_quote(line, quote);
+        }
+        count
+    }
+
+    #[test]
+    fn test_fixtures_have_the_same_lines_as_bash() {
+        for statement in fixtures::statements().iter() {
+            let bash = statement.to_bash().unwrap();
+            let sh = statement.to_sh().unwrap();
+            assert!(count_lines(bash.as_slice()) == count_lines(sh.as_slice()), "bash:\n{}\nsh:\n{}", bash, sh);
+        }
+    }
+
+    #[test]
+    fn test_fixtures_avoid_bashisms() {
+        for statement in fixtures::statements().iter() {
+            let sh = statement.to_sh().unwrap();
+            for bashism in ["[[", "<<<", "&>", "=~"].iter() {
+                assert!(!sh.as_slice().contains(*bashism), "{} in:\n{}", bashism, sh);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fixtures_parse() {
+        for statement in fixtures::statements().iter() {
+            assert_parses("bash", format!("{}{}\n{}", SCRIPT_HEADER, statement.to_bash().unwrap(), SCRIPT_FOOTER).as_slice());
+            assert_parses("sh", format!("{}{}\n{}", POSIX_SCRIPT_HEADER, statement.to_sh().unwrap(), POSIX_SCRIPT_FOOTER).as_slice());
+        }
+    }
+
+    #[test]
+    fn test_messages_show_the_display_instead_of_the_command() {
+        let policy = ast::RetryPolicy { attempts: 2, backoff: ast::FixedBackoff(0) };
+        let statement = ast::Cmd(ast::Raw("echo hunter2 > /dev/null; false".to_string()),
+                                 vec![ast::EchoOption, ast::DisplayOption("make [secure]".to_string()), ast::RetryPolicyOption(policy), ast::AssertOption]);
+        for shell in ["bash", "sh"].iter() {
+            let (status, output) = run(*shell, &statement);
+            assert_eq!(ExitStatus(2), status);
+            assert!(output.as_slice().contains("The command \"make [secure]\" failed. Retrying, 2 of 2."), "{}: {}", shell, output);
+            assert!(output.as_slice().contains("The command \"make [secure]\" failed 2 times."), "{}: {}", shell, output);
+            assert!(output.as_slice().contains("The command \"make [secure]\" failed and exited with 1"), "{}: {}", shell, output);
+            assert!(!output.as_slice().contains("hunter2"), "{}: {}", shell, output);
+        }
+    }
+
+    #[test]
+    fn test_nested_commands_keep_the_options_of_the_outer_one() {
+        let statement = ast_block! {
+            ast::Stage("script".to_string());
+            ast::Function("inner".to_string(), vec![], box ast_block! {
+                format_cmd!([EchoOption], "true");
+                format_cmd!("false");
+            });
+            ast::Cmd(ast::Call("inner".to_string(), vec![]), vec![ast::EchoOption, ast::DisplayOption("outer".to_string()), ast::AssertOption]);
+            format_cmd!("echo unreachable")
+        };
+        for shell in ["bash", "sh"].iter() {
+            let (status, output) = run(*shell, &statement);
+            assert_eq!(ExitStatus(2), status);
+            assert!(output.as_slice().contains("The command \"outer\" failed and exited with 1 during script."), "{}: {}", shell, output);
+            assert!(!output.as_slice().contains("unreachable"), "{}: {}", shell, output);
+        }
+    }
+
+    #[test]
+    fn test_locals_are_unset_after_the_function_returns() {
+        let statement = ast_block! {
+            ast::Assign("name".to_string(), "outer".to_string());
+            ast::Function("greet".to_string(), vec!["name".to_string()], box ast::Local("message".to_string(), "hello".to_string()));
+            cmd!(ast::Call("greet".to_string(), vec!["inner".to_string()]));
+            cmd!(ast::Raw("echo \"name=$name message=${message-unset}\"".to_string()))
+        };
+        let (status, output) = run("sh", &statement);
+        assert_eq!(ExitStatus(0), status);
+        assert!(output.as_slice().contains("name=outer message=unset"), "{}", output);
+    }
+
+    #[test]
+    fn test_multi_line_words_in_blocks() {
+        let statement = ast_if!(ast::Not(box ast::IsSet("UNSET".to_string())) {
+            ast_set!(MESSAGE = "one\ntwo\n".to_string());
+            cmd!(ast::Raw("printf '%s|' \"$MESSAGE\"".to_string()));
+            cmd!(ast::Raw("cat <<EOF\nheredoc body\nEOF".to_string()));
+        });
+        assert_eq!("if { ! [ -n \"${UNSET+x}\" ]; }; then\n  travis_cmd 'export MESSAGE='\\''one\ntwo\n'\\'''\n  travis_cmd 'printf '\\''%s|'\\'' \"$MESSAGE\"'\n  travis_cmd 'cat <<EOF\nheredoc body\nEOF'\nfi",
+                   statement.to_sh().unwrap().as_slice());
+        for shell in ["bash", "sh"].iter() {
+            let (status, output) = run(*shell, &statement);
+            assert_eq!(ExitStatus(0), status);
+            assert!(output.as_slice().starts_with("one\ntwo\n|heredoc body\n"), "{}: {}", shell, output);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Maps the lines of a rendered script back to the `ast::Traced` statements that produced them.
+
+use std::collections::TreeMap;
+use serialize::json;
+use serialize::json::{Json, ToJson};
+
+use ast;
+use bash::{RenderResult, ToBash};
+use payload::{Shell,Bash,Posix};
+use sh::ToSh;
+
+/// The lines of a script that a traced statement rendered to. Line numbers start at 1, and the
+/// last line is included.
+#[deriving(Clone, PartialEq, Show)]
+pub struct SourceMapping {
+    pub first_line: uint,
+    pub last_line: uint,
+    pub source: String,
+}
+
+impl ToJson for SourceMapping {
+    fn to_json(&self) -> Json {
+        let mut object = TreeMap::new();
+        object.insert("first_line".to_string(), self.first_line.to_json());
+        object.insert("last_line".to_string(), self.last_line.to_json());
+        object.insert("source".to_string(), self.source.to_json());
+        json::Object(object)
+    }
+}
+
+/// Lists where the traced statements in the AST end up when it's rendered for the given shell,
+/// with the output starting at `first_line`. Nested traces come after the traces they're in. This
+/// fails if the AST can't be rendered.
+pub fn source_map(statement: &ast::Statement, first_line: uint, shell: Shell) -> RenderResult<Vec<SourceMapping>> {
+    let mut mappings = vec![];
+    try!(map_statement(statement, first_line, shell, &mut mappings));
+    Ok(mappings)
+}
+
+/// Quoted strings can span lines, so this depends on the shell.
+fn line_count(statement: &ast::Statement, shell: Shell) -> RenderResult<uint> {
+    let rendered = match shell {
+        Bash => try!(statement.to_bash()),
+        Posix => try!(statement.to_sh()),
+    };
+    Ok(rendered.as_slice().split('\n').count())
+}
+
+/// This follows the layout `ToBash` and `ToSh` use: every statement that contains others puts a
+/// fixed number of lines before each of them.
+fn map_statement(statement: &ast::Statement, line: uint, shell: Shell, mappings: &mut Vec<SourceMapping>) -> RenderResult<()> {
+    match *statement {
+        ast::Statements(ref stmts) => {
+            let mut line = line;
+            for stmt in stmts.iter().filter(|s| !s.is_noop()) {
+                try!(map_statement(stmt, line, shell, mappings));
+                line += try!(line_count(stmt, shell));
+            }
+            Ok(())
+        },
+        ast::Fold(_, ref body) | ast::For(_, _, ref body) | ast::While(_, ref body) => map_statement(&**body, line + 1, shell, mappings),
+        ast::If(_, ref body, ref elsebody) => {
+            try!(map_statement(&**body, line + 1, shell, mappings));
+            let else_line = line + 1 + try!(line_count(&**body, shell));
+            match **elsebody {
+                ast::Noop => Ok(()),
+                // An `elif` continues on the line the `else` would be on.
+                ast::If(_, _, _) => map_statement(&**elsebody, else_line, shell, mappings),
+                _ => map_statement(&**elsebody, else_line + 1, shell, mappings),
+            }
+        },
+        // Each parameter is bound to a local on its own line.
+        ast::Function(_, ref params, ref body) => map_statement(&**body, line + 1 + params.len(), shell, mappings),
+        ast::Traced(ref source, ref body) => {
+            if !body.is_noop() {
+                let last_line = line + try!(line_count(&**body, shell)) - 1;
+                mappings.push(SourceMapping { first_line: line, last_line: last_line, source: source.clone() });
+            }
+            map_statement(&**body, line, shell, mappings)
+        },
+        ast::Cmd(_, _) | ast::Assign(_, _) | ast::Local(_, _) | ast::Stage(_) | ast::Noop => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SourceMapping, source_map};
+    use ast;
+    use bash::ToBash;
+    use payload::{Bash,Posix};
+    use sh::ToSh;
+
+    fn traced(source: &str, statement: ast::Statement) -> ast::Statement {
+        ast::Traced(source.to_string(), box statement)
+    }
+
+    /// Checks that the mapping covers the lines the traced statement was rendered to, in a script
+    /// where `bash` starts at `first_line`.
+    fn assert_lines(bash: &str, first_line: uint, mapping: &SourceMapping, expected: &[&str]) {
+        let lines: Vec<&str> = bash.split('\n').collect();
+        let mapped: Vec<&str> = lines.slice(mapping.first_line - first_line, mapping.last_line - first_line + 1).iter().map(|line| line.trim()).collect();
+        assert_eq!(expected, mapped.as_slice());
+    }
+
+    #[test]
+    fn test_maps_traced_statements() {
+        let statement = ast_block! {
+            format_cmd!("echo start");
+            traced("components::git", ast_block! {
+                format_cmd!("git clone");
+                format_cmd!("cd repo");
+            });
+            ast::Noop;
+            ast::Fold("script".to_string(), box traced("config.script[0]", format_cmd!("make")));
+        };
+        let bash = statement.to_bash().unwrap();
+        let mappings = source_map(&statement, 1, Bash).unwrap();
+
+        assert_eq!(vec![
+            SourceMapping { first_line: 2, last_line: 3, source: "components::git".to_string() },
+            SourceMapping { first_line: 5, last_line: 5, source: "config.script[0]".to_string() },
+        ], mappings);
+        assert_lines(bash.as_slice(), 1, &mappings[0], &["travis_cmd 'git clone'", "travis_cmd 'cd repo'"]);
+        assert_lines(bash.as_slice(), 1, &mappings[1], &["travis_cmd make"]);
+    }
+
+    #[test]
+    fn test_counts_lines_of_the_shell() {
+        // Bash quotes the newline as `$'\n'`, sh keeps it in single quotes.
+        let statement = ast_block! {
+            cmd!(ast::Envset("MESSAGE".to_string(), "one\ntwo".to_string()));
+            traced("config.script[0]", format_cmd!("make"));
+        };
+
+        assert_eq!(2, source_map(&statement, 1, Bash).unwrap()[0].first_line);
+        assert_eq!(3, source_map(&statement, 1, Posix).unwrap()[0].first_line);
+        assert_lines(statement.to_sh().unwrap().as_slice(), 1, &source_map(&statement, 1, Posix).unwrap()[0], &["travis_cmd make"]);
+    }
+
+    #[test]
+    fn test_maps_nested_statements() {
+        let statement = ast_block! {
+            ast_if!(ast::IsSet("A".to_string()) {
+                traced("config.after_success[0]", format_cmd!("./deploy"));
+            } else {
+                ast_if!(ast::IsSet("B".to_string()) {
+                    format_cmd!("one");
+                    format_cmd!("two");
+                } else {
+                    traced("config.after_failure[0]", format_cmd!("cat log"));
+                });
+            });
+            ast::Function("greet".to_string(), vec!["name".to_string()], box traced("languages::rust", format_cmd!("echo hi")));
+        };
+        let bash = statement.to_bash().unwrap();
+        let mappings = source_map(&statement, 10, Bash).unwrap();
+
+        let first_lines: Vec<uint> = mappings.iter().map(|mapping| mapping.first_line).collect();
+        assert_eq!(vec![11, 16, 20], first_lines);
+        assert_lines(bash.as_slice(), 10, &mappings[0], &["travis_cmd ./deploy"]);
+        assert_lines(bash.as_slice(), 10, &mappings[1], &["travis_cmd 'cat log'"]);
+        assert_lines(bash.as_slice(), 10, &mappings[2], &["travis_cmd 'echo hi'"]);
+    }
+
+    #[test]
+    fn test_skips_empty_traces() {
+        let statement = ast_block! {
+            traced("components::services", ast::Noop);
+            format_cmd!("make");
+        };
+
+        assert_eq!(Vec::<SourceMapping>::new(), source_map(&statement, 1, Bash).unwrap());
+    }
+}
@@ -1,7 +1,10 @@
 #![cfg(test)]
 
 use ast;
-use std::collections::HashMap;
+use bash::ToBash;
+use putfile;
+use regex::Regex;
+use std::collections::{HashMap,HashSet};
 
 pub type Directory = HashMap<String, DirectoryEntry>;
 
@@ -11,11 +14,29 @@ pub enum DirectoryEntry {
     File(Vec<u8>)
 }
 
+/// The number of times a `While` loop may run before the runner assumes it never ends.
+static MAX_ITERATIONS: uint = 1000;
+
 pub struct TestAstRunner {
     pub fs_state: DirectoryEntry,
+    /// The modes `Putfile` gave files, by their full path.
+    pub file_modes: HashMap<Path, u32>,
     pub working_directory: Path,
     pub commands: Vec<(String, Vec<ast::CommandOption>)>,
+    /// The `Exec` commands that were run. They are also in `commands`, with their arguments joined by spaces.
+    pub pipelines: Vec<(ast::Pipeline, Vec<ast::CommandOption>)>,
     pub environment_vars: HashMap<String, String>,
+    /// Variables that were set without being exported.
+    pub shell_vars: HashMap<String, String>,
+    pub functions: HashMap<String, (Vec<String>, ast::Statement)>,
+    /// The programs that `CommandAvailable` finds on the `PATH`.
+    pub available_commands: HashSet<String>,
+    /// The exit status of commands, by their text. Commands that aren't in here succeed, except `false`.
+    pub command_results: HashMap<String, int>,
+    /// The exit status of the last command that was run, like `$?`.
+    pub last_status: int,
+    /// For every function that is running, the values its local variables had before the call.
+    frames: Vec<Vec<(String, Option<String>)>>,
 }
 
 impl DirectoryEntry {
@@ -48,9 +69,17 @@ impl TestAstRunner {
 
         let mut runner = TestAstRunner {
             fs_state: Dir(fs),
+            file_modes: HashMap::new(),
             commands: Vec::new(),
+            pipelines: Vec::new(),
             working_directory: Path::new("/home/travis"),
             environment_vars: HashMap::new(),
+            shell_vars: HashMap::new(),
+            functions: HashMap::new(),
+            available_commands: HashSet::new(),
+            command_results: HashMap::new(),
+            last_status: 0,
+            frames: Vec::new(),
         };
 
         runner.mkdir(&Path::new("/home"));
@@ -63,21 +92,75 @@ impl TestAstRunner {
         self.run_statement(script);
     }
 
+    /// Returns the options of the first command that was run with the given text.
+    pub fn options_for<'a>(&'a self, command: &str) -> Option<&'a Vec<ast::CommandOption>> {
+        self.commands.iter().find(|&&(ref cmd, _)| cmd.as_slice() == command).map(|&(_, ref opts)| opts)
+    }
+
+    /// Returns the value of a variable, whether it was exported or not.
+    pub fn var<'a>(&'a self, name: &str) -> Option<&'a String> {
+        let name = name.to_string();
+        self.shell_vars.find(&name).or_else(|| self.environment_vars.find(&name))
+    }
+
+    /// Replaces references to variables that are set, like `$NAME` and `${NAME}`, with their values.
+    ///
+    /// References to variables that aren't set are left alone, since they may refer to variables of
+    /// the build environment that the runner doesn't know about.
+    pub fn expand(&self, input: &str) -> String {
+        let chars: Vec<char> = input.chars().collect();
+        let mut result = String::new();
+        let mut pos = 0;
+
+        while pos < chars.len() {
+            if chars[pos] != '$' {
+                result.push(chars[pos]);
+                pos += 1;
+                continue;
+            }
+
+            let braced = pos + 1 < chars.len() && chars[pos + 1] == '{';
+            let start = if braced { pos + 2 } else { pos + 1 };
+            let mut end = start;
+            while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+                end += 1;
+            }
+
+            let name = String::from_chars(chars.slice(start, end));
+            let closed = !braced || (end < chars.len() && chars[end] == '}');
+            match self.var(name.as_slice()) {
+                Some(value) if !name.is_empty() && closed => {
+                    result.push_str(value.as_slice());
+                    pos = if braced { end + 1 } else { end };
+                },
+                _ => {
+                    result.push('$');
+                    pos += 1;
+                },
+            }
+        }
+
+        result
+    }
+
+    /// Creates the directory and any missing parents, like `mkdir -p`.
     pub fn mkdir(&mut self, path: &Path) {
-        let dir_path = self.working_directory.join(path).dir_path();
-        let path_parts: Vec<&str> = dir_path.str_components().map(|c| c.unwrap()).collect();
-        let parent_dir = self.fs_state.walk_mut(path_parts.as_slice());
-        match parent_dir {
-            Some(entry) => match *entry {
-                Dir(ref mut d) => {
-                    if !d.contains_key(&path.filename_str().unwrap().to_string()) {
-                        d.insert(path.filename_str().unwrap().to_string(), Dir(HashMap::new()));
-                    };
-                }
-                File(_) => panic!("attempting to mkdir in a subdir of a file"),
-            },
-            None => panic!("attempting to mkdir in a subdir of non-existant"),
-        };
+        let full_path = self.working_directory.join(path);
+        let path_parts: Vec<&str> = full_path.str_components().map(|c| c.unwrap()).collect();
+        for i in range(0, path_parts.len()) {
+            let parent_dir = self.fs_state.walk_mut(path_parts.slice_to(i));
+            match parent_dir {
+                Some(entry) => match *entry {
+                    Dir(ref mut d) => {
+                        if !d.contains_key(&path_parts[i].to_string()) {
+                            d.insert(path_parts[i].to_string(), Dir(HashMap::new()));
+                        };
+                    }
+                    File(_) => panic!("attempting to mkdir in a subdir of a file"),
+                },
+                None => panic!("attempting to mkdir in a subdir of non-existant"),
+            };
+        }
     }
 
     pub fn put_file(&mut self, path: &Path, body: &[u8]) {
@@ -93,6 +176,34 @@ impl TestAstRunner {
         };
     }
 
+    /// Returns the contents of the file, if there is one.
+    pub fn read_file(&self, path: &Path) -> Option<Vec<u8>> {
+        let full_path = self.working_directory.join(path);
+        let path_parts: Vec<&str> = full_path.str_components().map(|c| c.unwrap()).collect();
+        match self.fs_state.walk(path_parts.as_slice()) {
+            Some(&File(ref body)) => Some(body.clone()),
+            _ => None,
+        }
+    }
+
+    /// Writes the file from the chunks the script would write it from.
+    fn putfile(&mut self, path: &Path, contents: &[u8], options: &ast::PutfileOptions) {
+        let mut body = if options.append { self.read_file(path).unwrap_or(vec![]) } else { vec![] };
+        for chunk in putfile::chunks(contents, options).iter() {
+            match putfile::decode_chunk(chunk.as_slice(), options) {
+                Ok(decoded) => body.push_all(decoded.as_slice()),
+                Err(e) => panic!("couldn't decode a chunk of {}: {}", path.display(), e),
+            }
+        }
+        self.put_file(path, body.as_slice());
+
+        match options.mode {
+            Some(mode) => { self.file_modes.insert(self.working_directory.join(path), mode); },
+            None => {},
+        }
+        self.last_status = 0;
+    }
+
     fn run_statement(&mut self, statement: &ast::Statement) {
         match *statement {
             ast::Statements(ref stmts) => {
@@ -103,26 +214,136 @@ impl TestAstRunner {
             ast::Fold(_, box ref stmt) => self.run_statement(stmt),
             ast::Cmd(ref cmd, ref opts) => self.run_command(cmd, opts),
             ast::If(ref cond, box ref thenbody, box ref elsebody) => self.run_if(cond, thenbody, elsebody),
+            ast::For(ref var, ref values, box ref body) => {
+                for value in values.iter() {
+                    self.assign(var.as_slice(), value.as_slice());
+                    self.run_statement(body);
+                }
+            },
+            ast::While(ref cond, box ref body) => {
+                let mut iterations = 0;
+                while self.eval_condition(cond) {
+                    iterations += 1;
+                    if iterations > MAX_ITERATIONS {
+                        panic!("while loop ran more than {} times", MAX_ITERATIONS);
+                    }
+                    self.run_statement(body);
+                }
+            },
+            ast::Function(ref name, ref params, box ref body) => {
+                self.functions.insert(name.clone(), (params.clone(), body.clone()));
+            },
+            ast::Assign(ref var, ref value) => self.assign(var.as_slice(), value.as_slice()),
+            ast::Local(ref var, ref value) => self.local(var.as_slice(), value.as_slice()),
+            ast::Traced(_, box ref body) => self.run_statement(body),
+            ast::Stage(ref name) => { self.environment_vars.insert("TRAVIS_STAGE".to_string(), name.clone()); },
             ast::Noop => {}
         }
     }
 
+    fn assign(&mut self, var: &str, value: &str) {
+        if self.environment_vars.contains_key(&var.to_string()) {
+            self.environment_vars.insert(var.to_string(), value.to_string());
+        } else {
+            self.shell_vars.insert(var.to_string(), value.to_string());
+        }
+    }
+
+    fn local(&mut self, var: &str, value: &str) {
+        let previous = self.shell_vars.find(&var.to_string()).map(|v| v.clone());
+        match self.frames.last_mut() {
+            Some(frame) => if !frame.iter().any(|&(ref name, _)| name.as_slice() == var) {
+                frame.push((var.to_string(), previous));
+            },
+            None => panic!("local {} used outside of a function", var),
+        }
+        self.shell_vars.insert(var.to_string(), value.to_string());
+    }
+
+    fn call(&mut self, name: &str, args: &Vec<String>) {
+        let (params, body) = match self.functions.find(&name.to_string()) {
+            Some(&(ref params, ref body)) => (params.clone(), body.clone()),
+            None => panic!("function {} isn't defined", name),
+        };
+
+        self.frames.push(Vec::new());
+        for (i, param) in params.iter().enumerate() {
+            let arg = if i < args.len() { args[i].clone() } else { String::new() };
+            self.local(param.as_slice(), arg.as_slice());
+        }
+        self.run_statement(&body);
+
+        for (name, previous) in self.frames.pop().unwrap().into_iter() {
+            match previous {
+                Some(value) => self.shell_vars.insert(name, value),
+                None => self.shell_vars.remove(&name),
+            };
+        }
+    }
+
     fn run_command(&mut self, command: &ast::Command, opts: &Vec<ast::CommandOption>) {
         match *command {
-            ast::Raw(ref cmd) => self.commands.push((cmd.clone(), opts.clone())),
-            ast::Echo(ref text) => self.commands.push((format!("echo {}", text), opts.clone())),
-            ast::Newline => self.commands.push(("echo".to_string(), opts.clone())),
+            ast::Raw(_) | ast::Echo(_) | ast::Newline => self.record(command, opts),
             ast::Envset(ref key, ref value) => {
+                self.shell_vars.remove(key);
                 self.environment_vars.insert(key.clone(), value.clone());
+                self.last_status = 0;
             },
             // TODO: Check if path exists
             ast::Cd(ref path) => self.working_directory = self.working_directory.join(path),
-            ast::Putfile(ref path, ref body) => self.put_file(path, body.as_slice()),
+            ast::Putfile(ref path, ref contents, ref options) => self.putfile(path, contents.as_slice(), options),
             ast::Mkdir(ref path) => self.mkdir(path),
+            ast::Call(ref name, ref args) => {
+                self.record(command, opts);
+                self.call(name.as_slice(), args);
+            },
+            ast::Exec(ref pipeline) => {
+                self.record(command, opts);
+                self.pipelines.push((pipeline.clone(), opts.clone()));
+            },
             _ => unimplemented!(),
         }
     }
 
+    /// Adds the command to `commands` and sets its exit status, reporting it if it has `ResultOption`.
+    fn record(&mut self, command: &ast::Command, opts: &Vec<ast::CommandOption>) {
+        let text = self.command_text(command);
+        self.last_status = self.status_of(text.as_slice());
+        self.commands.push((text, opts.clone()));
+
+        if opts.contains(&ast::ResultOption) {
+            let failed = self.last_status != 0 || self.var("TRAVIS_TEST_RESULT").map_or(false, |result| result.as_slice() != "0");
+            self.environment_vars.insert("TRAVIS_TEST_RESULT".to_string(), if failed { "1" } else { "0" }.to_string());
+        }
+    }
+
+    /// The exit status of a command. `false` fails unless `command_results` says otherwise.
+    fn status_of(&self, text: &str) -> int {
+        match self.command_results.find(&text.to_string()) {
+            Some(&status) => status,
+            None if text == "false" => 1,
+            None => 0,
+        }
+    }
+
+    /// The text a command is recorded as in `commands`, with the variables that are set expanded.
+    fn command_text(&self, command: &ast::Command) -> String {
+        match *command {
+            ast::Raw(ref cmd) => self.expand(cmd.as_slice()),
+            ast::Echo(ref text) => format!("echo {}", self.expand(text.as_slice())),
+            ast::Call(ref name, ref args) => {
+                let mut words = vec![name.clone()];
+                words.push_all(args.as_slice());
+                words.connect(" ")
+            },
+            ast::Exec(ref pipeline) => {
+                let commands: Vec<String> = pipeline.commands.iter().map(|command| command.argv.connect(" ")).collect();
+                commands.connect(" | ")
+            },
+            _ => command.to_bash().unwrap(),
+        }
+    }
+
     fn run_if(&mut self, condition: &ast::Condition, thenbody: &ast::Statement, elsebody: &ast::Statement) {
         if self.eval_condition(condition) {
             self.run_statement(thenbody);
@@ -154,10 +375,156 @@ impl TestAstRunner {
                     _ => false,
                 }
             },
-            ast::CmdCond(_) => unimplemented!(),
+            ast::CmdCond(ref command) => {
+                let text = self.command_text(command);
+                self.last_status = self.status_of(text.as_slice());
+                self.last_status == 0
+            },
+            ast::IsSet(ref var) => self.var(var.as_slice()).is_some(),
+            ast::VarEquals(ref var, ref value) => self.var(var.as_slice()) == Some(value),
+            ast::StrEquals(ref left, ref right) => self.expand(left.as_slice()) == self.expand(right.as_slice()),
+            ast::Matches(ref string, ref regex) => match Regex::new(regex.as_slice()) {
+                Ok(re) => re.is_match(self.expand(string.as_slice()).as_slice()),
+                Err(e) => panic!("invalid regex {}: {}", regex, e),
+            },
+            ast::CommandAvailable(ref command) => self.available_commands.contains(command) || self.functions.contains_key(command),
+            ast::LastSucceeded => self.last_status == 0,
             ast::And(box ref cond1, box ref cond2) => self.eval_condition(cond1) && self.eval_condition(cond2),
             ast::Or(box ref cond1, box ref cond2) => self.eval_condition(cond1) || self.eval_condition(cond2),
             ast::Not(box ref cond) => !self.eval_condition(cond),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::TestAstRunner;
+    use ast;
+
+    fn commands(runner: &TestAstRunner) -> Vec<&str> {
+        runner.commands.iter().map(|&(ref command, _)| command.as_slice()).collect()
+    }
+
+    #[test]
+    fn test_for() {
+        let mut runner = TestAstRunner::new();
+        runner.run(&ast::For("toolchain".to_string(), vec!["stable".to_string(), "nightly".to_string()],
+                             box format_cmd!("cargo +$toolchain test ${toolchain}-suite $HOME")));
+
+        assert_eq!(vec!["cargo +stable test stable-suite $HOME", "cargo +nightly test nightly-suite $HOME"], commands(&runner));
+        assert_eq!(Some(&"nightly".to_string()), runner.shell_vars.find(&"toolchain".to_string()));
+    }
+
+    #[test]
+    fn test_while() {
+        let mut runner = TestAstRunner::new();
+        runner.run(&ast::While(!ast::Exists(Path::new("done")), box ast_block! {
+            format_cmd!("wait");
+            cmd!(ast::Putfile(Path::new("done"), vec![], ast::PutfileOptions::default()));
+        }));
+
+        assert_eq!(vec!["wait"], commands(&runner));
+    }
+
+    #[test]
+    fn test_putfile() {
+        let mut runner = TestAstRunner::new();
+        runner.run(&ast_block! {
+            cmd!(ast::Putfile(Path::new("log"), b"one\n".to_vec(), ast::PutfileOptions::default().compress()));
+            cmd!(ast::Putfile(Path::new("log"), b"two\n".to_vec(), ast::PutfileOptions::default().append()));
+            cmd!(ast::Putfile(Path::new("key"), b"secret".to_vec(), ast::PutfileOptions::default().mode(0o600)));
+        });
+
+        assert_eq!(Some(b"one\ntwo\n".to_vec()), runner.read_file(&Path::new("log")));
+        assert_eq!(Some(&0o600), runner.file_modes.find(&Path::new("/home/travis/key")));
+        assert_eq!(None, runner.file_modes.find(&Path::new("/home/travis/log")));
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_endless_while() {
+        let mut runner = TestAstRunner::new();
+        runner.run(&ast::While(ast::Exists(Path::new("/home")), box ast::Noop));
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut runner = TestAstRunner::new();
+        runner.run(&ast_block! {
+            ast::Assign("name".to_string(), "outer".to_string());
+            ast::Function("greet".to_string(), vec!["name".to_string()], box ast_block! {
+                ast::Local("greeting".to_string(), "hello".to_string());
+                cmd!(ast::Echo("$greeting, $name".to_string()));
+            });
+            cmd!([EchoOption], ast::Call("greet".to_string(), vec!["world".to_string()]));
+            cmd!(ast::Echo("$name".to_string()));
+        });
+
+        assert_eq!(vec!["greet world", "echo hello, world", "echo outer"], commands(&runner));
+        assert_eq!(None, runner.var("greeting"));
+    }
+
+    #[test]
+    fn test_exec() {
+        let mut runner = TestAstRunner::new();
+        let pipeline = ast::Pipeline::new(vec![
+            ast::SimpleCommand::new(&["cat", "a file"]),
+            ast::SimpleCommand::new(&["grep", "-v", "$HOME"]).redirect(ast::StderrToStdout),
+        ]);
+        runner.run(&cmd!([EchoOption], ast::Exec(pipeline)));
+
+        assert_eq!(vec!["cat a file | grep -v $HOME"], commands(&runner));
+        let (ref pipeline, ref options) = runner.pipelines[0];
+        assert_eq!(vec!["grep".to_string(), "-v".to_string(), "$HOME".to_string()], pipeline.commands[1].argv);
+        assert_eq!(&vec![ast::EchoOption], options);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut runner = TestAstRunner::new();
+        runner.available_commands.insert("cargo".to_string());
+        runner.command_results.insert("false".to_string(), 1);
+        runner.run(&ast_block! {
+            ast_set!(VERSION = "1.2".to_string());
+            ast::Assign("EMPTY".to_string(), "".to_string());
+        });
+
+        assert!(runner.eval_condition(&ast::IsSet("EMPTY".to_string())));
+        assert!(!runner.eval_condition(&ast::IsSet("MISSING".to_string())));
+        assert!(runner.eval_condition(&ast::VarEquals("VERSION".to_string(), "1.2".to_string())));
+        assert!(!runner.eval_condition(&ast::VarEquals("MISSING".to_string(), "".to_string())));
+        assert!(runner.eval_condition(&ast::StrEquals("v$VERSION".to_string(), "v1.2".to_string())));
+        assert!(runner.eval_condition(&ast::Matches("${VERSION}".to_string(), "^1\\.[0-9]+$".to_string())));
+        assert!(!runner.eval_condition(&ast::Matches("$VERSION".to_string(), "^2".to_string())));
+        assert!(runner.eval_condition(&ast::CommandAvailable("cargo".to_string())));
+        assert!(!runner.eval_condition(&ast::CommandAvailable("make".to_string())));
+        assert!(runner.eval_condition(&ast::CmdCond(ast::Raw("true".to_string()))));
+        assert!(runner.eval_condition(&ast::LastSucceeded));
+        assert!(!runner.eval_condition(&ast::CmdCond(ast::Raw("false".to_string()))));
+        assert!(!runner.eval_condition(&ast::LastSucceeded));
+    }
+
+    #[test]
+    fn test_result_option_reports_failures() {
+        let mut runner = TestAstRunner::new();
+        runner.command_results.insert("make test".to_string(), 2);
+        runner.run(&ast_block! {
+            format_cmd!([ResultOption], "make test");
+            format_cmd!([ResultOption], "make lint");
+        });
+
+        assert_eq!(Some(&"1".to_string()), runner.environment_vars.find(&"TRAVIS_TEST_RESULT".to_string()));
+        assert_eq!(0, runner.last_status);
+    }
+
+    #[test]
+    fn test_assign_updates_exported_variable() {
+        let mut runner = TestAstRunner::new();
+        runner.run(&ast_block! {
+            ast_set!(FOO = "exported".to_string());
+            ast::Assign("FOO".to_string(), "changed".to_string());
+        });
+
+        assert_eq!(Some(&"changed".to_string()), runner.environment_vars.find(&"FOO".to_string()));
+    }
+}
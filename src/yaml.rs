@@ -0,0 +1,667 @@
+//! A parser for the subset of YAML used in `.travis.yml` files.
+//!
+//! Documents are parsed into `json::Json` values, so they can be decoded with the same code as
+//! JSON payloads. Block mappings and sequences, flow collections (`[a, b]` and `{a: b}`), quoted
+//! and plain scalars, and literal (`|`) and folded (`>`) block scalars are supported. Anchors,
+//! aliases, tags and multiple documents are not.
+//!
+//! Plain scalars that look like numbers are kept as strings, since turning `rust: 1.0` into a
+//! float would lose the text that was written. Fields that take numbers accept these strings.
+
+use serialize::json;
+use std::char;
+use std::collections::TreeMap;
+use std::fmt;
+
+pub struct YamlError {
+    /// The line the error was found on, starting at 1.
+    pub line: uint,
+    pub message: String,
+}
+
+pub type YamlResult<T> = Result<T, YamlError>;
+
+impl fmt::Show for YamlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Parses a YAML document.
+pub fn parse(input: &str) -> YamlResult<json::Json> {
+    let mut parser = Parser::new(input);
+
+    if parser.lines.is_empty() {
+        return Ok(json::Null);
+    }
+
+    let indent = parser.lines[0].indent;
+    let value = try!(parser.parse_node(indent));
+
+    match parser.lines.as_slice().get(parser.pos) {
+        Some(line) => Err(error(line.number, "unexpected content after the end of the document")),
+        None => Ok(value),
+    }
+}
+
+fn error(number: uint, message: &str) -> YamlError {
+    YamlError { line: number + 1, message: message.to_string() }
+}
+
+/// A line that isn't empty or a comment.
+struct Line {
+    /// The index of the line in the input.
+    number: uint,
+    indent: uint,
+    /// The contents of the line without indentation and comments.
+    text: String,
+}
+
+struct Parser<'a> {
+    raw_lines: Vec<&'a str>,
+    lines: Vec<Line>,
+    pos: uint,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Parser<'a> {
+        let raw_lines: Vec<&str> = input.lines().collect();
+        let mut lines = Vec::new();
+
+        for (number, raw) in raw_lines.iter().enumerate() {
+            let text = strip_comment(*raw);
+            let trimmed = text.trim();
+            if trimmed.is_empty() || (lines.is_empty() && trimmed == "---") {
+                continue;
+            }
+
+            lines.push(Line {
+                number: number,
+                indent: indent_of(text),
+                text: trimmed.to_string(),
+            });
+        }
+
+        Parser { raw_lines: raw_lines, lines: lines, pos: 0 }
+    }
+
+    fn current(&self) -> Option<&Line> {
+        self.lines.as_slice().get(self.pos)
+    }
+
+    /// Parses the node starting at the current line, which is indented by `indent`.
+    fn parse_node(&mut self, indent: uint) -> YamlResult<json::Json> {
+        let (number, text) = match self.current() {
+            Some(line) => (line.number, line.text.clone()),
+            None => return Ok(json::Null),
+        };
+
+        if is_sequence_item(text.as_slice()) {
+            self.parse_sequence(indent)
+        } else if find_mapping_colon(text.as_slice()).is_some() {
+            self.parse_mapping(indent)
+        } else {
+            self.pos += 1;
+            parse_inline(text.as_slice(), number)
+        }
+    }
+
+    /// Parses the value of a key or sequence item that continues on the following lines.
+    fn parse_nested(&mut self, parent_indent: uint, allow_sequence: bool) -> YamlResult<json::Json> {
+        let (indent, text) = match self.current() {
+            Some(line) => (line.indent, line.text.clone()),
+            None => return Ok(json::Null),
+        };
+
+        if indent > parent_indent {
+            self.parse_node(indent)
+        } else if allow_sequence && indent == parent_indent && is_sequence_item(text.as_slice()) {
+            self.parse_sequence(indent)
+        } else {
+            Ok(json::Null)
+        }
+    }
+
+    fn parse_sequence(&mut self, indent: uint) -> YamlResult<json::Json> {
+        let mut items = Vec::new();
+
+        loop {
+            let (number, line_indent, text) = match self.current() {
+                Some(line) => (line.number, line.indent, line.text.clone()),
+                None => break,
+            };
+
+            if line_indent < indent || (line_indent == indent && !is_sequence_item(text.as_slice())) {
+                break;
+            } else if line_indent > indent {
+                return Err(error(number, "unexpected indentation"));
+            }
+
+            let rest = text.as_slice().slice_from(1).trim_left();
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(try!(self.parse_nested(indent, false)));
+            } else if rest.starts_with("|") || rest.starts_with(">") {
+                items.push(try!(self.parse_block_scalar(indent, rest, number)));
+            } else {
+                // Treat the rest of the line as if it started on a line of its own, so a mapping
+                // can continue on the following lines at the same column.
+                let item_indent = indent + text.len() - rest.len();
+                self.lines.as_mut_slice()[self.pos] = Line { number: number, indent: item_indent, text: rest.to_string() };
+                items.push(try!(self.parse_node(item_indent)));
+            }
+        }
+
+        Ok(json::List(items))
+    }
+
+    fn parse_mapping(&mut self, indent: uint) -> YamlResult<json::Json> {
+        let mut map = TreeMap::new();
+
+        loop {
+            let (number, line_indent, text) = match self.current() {
+                Some(line) => (line.number, line.indent, line.text.clone()),
+                None => break,
+            };
+
+            if line_indent < indent {
+                break;
+            } else if line_indent > indent {
+                return Err(error(number, "unexpected indentation"));
+            }
+
+            let colon = match find_mapping_colon(text.as_slice()) {
+                Some(colon) => colon,
+                None => return Err(error(number, "expected a mapping key")),
+            };
+
+            let key = match try!(parse_inline(text.as_slice().slice_to(colon).trim(), number)) {
+                json::String(key) => key,
+                json::Null => "null".to_string(),
+                other => other.to_string(),
+            };
+            if map.contains_key(&key) {
+                return Err(error(number, format!("duplicate key {}", key).as_slice()));
+            }
+
+            let value_str = text.as_slice().slice_from(colon + 1).trim();
+            let value = if value_str.is_empty() {
+                self.pos += 1;
+                try!(self.parse_nested(indent, true))
+            } else if value_str.starts_with("|") || value_str.starts_with(">") {
+                try!(self.parse_block_scalar(indent, value_str, number))
+            } else {
+                self.pos += 1;
+                try!(parse_inline(value_str, number))
+            };
+
+            map.insert(key, value);
+        }
+
+        Ok(json::Object(map))
+    }
+
+    /// Parses a literal (`|`) or folded (`>`) block scalar, whose header is on line `number`.
+    fn parse_block_scalar(&mut self, parent_indent: uint, header: &str, number: uint) -> YamlResult<json::Json> {
+        let literal = header.starts_with("|");
+        let chomping = header.slice_from(1);
+        if chomping != "" && chomping != "-" && chomping != "+" {
+            return Err(error(number, "unsupported block scalar header"));
+        }
+
+        let mut content: Vec<&str> = Vec::new();
+        let mut block_indent = None;
+        let mut raw = number + 1;
+
+        while raw < self.raw_lines.len() {
+            let line = self.raw_lines[raw];
+            if line.trim().is_empty() {
+                content.push("");
+                raw += 1;
+                continue;
+            }
+
+            let indent = indent_of(line);
+            if indent <= parent_indent {
+                break;
+            }
+            if block_indent.is_none() {
+                block_indent = Some(indent);
+            }
+            let level = block_indent.unwrap();
+            if indent < level {
+                break;
+            }
+
+            content.push(line.slice_from(level));
+            raw += 1;
+        }
+
+        while self.pos < self.lines.len() && self.lines[self.pos].number < raw {
+            self.pos += 1;
+        }
+
+        let mut trailing_newlines = 0u;
+        while content.last().map_or(false, |line| line.is_empty()) {
+            content.pop();
+            trailing_newlines += 1;
+        }
+
+        let mut result = String::new();
+        let mut previous_empty = true;
+        for (i, line) in content.iter().enumerate() {
+            if literal {
+                if i > 0 {
+                    result.push('\n');
+                }
+                result.push_str(*line);
+            } else if line.is_empty() {
+                result.push('\n');
+                previous_empty = true;
+            } else {
+                if !previous_empty {
+                    result.push(' ');
+                }
+                result.push_str(*line);
+                previous_empty = false;
+            }
+        }
+
+        if !content.is_empty() {
+            match chomping {
+                "-" => {},
+                "+" => {
+                    result.push('\n');
+                    for _ in range(0, trailing_newlines) {
+                        result.push('\n');
+                    }
+                },
+                _ => result.push('\n'),
+            }
+        }
+
+        Ok(json::String(result))
+    }
+}
+
+fn indent_of(line: &str) -> uint {
+    line.len() - line.trim_left_chars(' ').len()
+}
+
+fn is_sequence_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// Removes a comment from the end of the line, ignoring `#` characters in quoted strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+
+    for (i, ch) in line.char_indices() {
+        match quote {
+            Some(q) if ch == q => quote = None,
+            Some(_) => {},
+            None if (ch == '"' || ch == '\'') && starts_scalar(previous) => quote = Some(ch),
+            None if ch == '#' && previous.is_whitespace() => return line.slice_to(i),
+            None => {},
+        }
+        previous = ch;
+    }
+
+    line
+}
+
+fn starts_scalar(previous: char) -> bool {
+    previous.is_whitespace() || previous == '[' || previous == '{' || previous == ','
+}
+
+/// Finds the colon that separates a mapping key from its value, if the line is a mapping entry.
+fn find_mapping_colon(text: &str) -> Option<uint> {
+    if text.starts_with("[") || text.starts_with("{") {
+        return None;
+    }
+
+    let mut quote = None;
+    let mut chars = text.char_indices().peekable();
+
+    loop {
+        let (i, ch) = match chars.next() {
+            Some(next) => next,
+            None => return None,
+        };
+
+        match quote {
+            Some(q) if ch == q => quote = None,
+            Some(_) => {},
+            None if (ch == '"' || ch == '\'') && i == 0 => quote = Some(ch),
+            None if ch == ':' => match chars.peek() {
+                None => return Some(i),
+                Some(&(_, next)) if next.is_whitespace() => return Some(i),
+                _ => {},
+            },
+            None => {},
+        }
+    }
+}
+
+/// Parses a value that is entirely on one line: a scalar or a flow collection.
+fn parse_inline(text: &str, number: uint) -> YamlResult<json::Json> {
+    let mut parser = FlowParser { chars: text.chars().collect(), pos: 0, number: number };
+    let value = try!(parser.parse_value(false));
+
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(error(number, "unexpected characters after value"));
+    }
+
+    Ok(value)
+}
+
+struct FlowParser {
+    chars: Vec<char>,
+    pos: uint,
+    number: uint,
+}
+
+impl FlowParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.as_slice().get(self.pos).map(|ch| *ch)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, |ch| ch.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> YamlResult<()> {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(error(self.number, format!("expected '{}'", expected).as_slice()))
+        }
+    }
+
+    fn parse_value(&mut self, in_flow: bool) -> YamlResult<json::Json> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('[') => self.parse_flow_sequence(),
+            Some('{') => self.parse_flow_mapping(),
+            Some('"') => self.parse_double_quoted().map(json::String),
+            Some('\'') => self.parse_single_quoted().map(json::String),
+            _ => Ok(resolve_plain(self.parse_plain(in_flow, false).as_slice())),
+        }
+    }
+
+    fn parse_flow_sequence(&mut self) -> YamlResult<json::Json> {
+        let mut items = Vec::new();
+        try!(self.expect('['));
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                break;
+            }
+
+            items.push(try!(self.parse_value(true)));
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {},
+                _ => return Err(error(self.number, "expected ',' or ']' in flow sequence")),
+            }
+        }
+
+        Ok(json::List(items))
+    }
+
+    fn parse_flow_mapping(&mut self) -> YamlResult<json::Json> {
+        let mut map = TreeMap::new();
+        try!(self.expect('{'));
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some('}') {
+                self.pos += 1;
+                break;
+            }
+
+            let key = match self.peek() {
+                Some('"') => try!(self.parse_double_quoted()),
+                Some('\'') => try!(self.parse_single_quoted()),
+                _ => self.parse_plain(true, true),
+            };
+            try!(self.expect(':'));
+            let value = try!(self.parse_value(true));
+            map.insert(key, value);
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {},
+                _ => return Err(error(self.number, "expected ',' or '}' in flow mapping")),
+            }
+        }
+
+        Ok(json::Object(map))
+    }
+
+    /// Reads a plain scalar. Inside flow collections it ends at the next indicator, otherwise at the end of the line.
+    fn parse_plain(&mut self, in_flow: bool, is_key: bool) -> String {
+        let mut result = String::new();
+
+        loop {
+            match self.peek() {
+                None => break,
+                Some(',') | Some(']') | Some('}') if in_flow => break,
+                Some(':') if is_key => break,
+                Some(ch) => {
+                    result.push(ch);
+                    self.pos += 1;
+                },
+            }
+        }
+
+        result.as_slice().trim().to_string()
+    }
+
+    fn parse_single_quoted(&mut self) -> YamlResult<String> {
+        let mut result = String::new();
+        try!(self.expect('\''));
+
+        loop {
+            match self.peek() {
+                None => return Err(error(self.number, "unterminated single-quoted string")),
+                Some('\'') => {
+                    self.pos += 1;
+                    if self.peek() == Some('\'') {
+                        result.push('\'');
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                },
+                Some(ch) => {
+                    result.push(ch);
+                    self.pos += 1;
+                },
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn parse_double_quoted(&mut self) -> YamlResult<String> {
+        let mut result = String::new();
+        try!(self.expect('"'));
+
+        loop {
+            let ch = match self.peek() {
+                None => return Err(error(self.number, "unterminated double-quoted string")),
+                Some(ch) => ch,
+            };
+            self.pos += 1;
+
+            match ch {
+                '"' => break,
+                '\\' => {
+                    let escaped = match self.peek() {
+                        None => return Err(error(self.number, "unterminated double-quoted string")),
+                        Some(escaped) => escaped,
+                    };
+                    self.pos += 1;
+
+                    match escaped {
+                        'n' => result.push('\n'),
+                        't' => result.push('\t'),
+                        'r' => result.push('\r'),
+                        '0' => result.push('\0'),
+                        'e' => result.push('\x1b'),
+                        '"' | '\\' | '/' | ' ' => result.push(escaped),
+                        'x' => result.push(try!(self.parse_hex_escape(2))),
+                        'u' => result.push(try!(self.parse_hex_escape(4))),
+                        _ => return Err(error(self.number, format!("unknown escape sequence \\{}", escaped).as_slice())),
+                    }
+                },
+                _ => result.push(ch),
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn parse_hex_escape(&mut self, digits: uint) -> YamlResult<char> {
+        let mut code = 0u32;
+
+        for _ in range(0, digits) {
+            let digit = match self.peek().and_then(|ch| ch.to_digit(16)) {
+                Some(digit) => digit,
+                None => return Err(error(self.number, "invalid hexadecimal escape sequence")),
+            };
+            code = code * 16 + digit as u32;
+            self.pos += 1;
+        }
+
+        char::from_u32(code).ok_or(error(self.number, "invalid unicode escape sequence"))
+    }
+}
+
+/// Resolves the type of a plain scalar. Numbers stay strings, see the module documentation.
+fn resolve_plain(text: &str) -> json::Json {
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => json::Null,
+        "true" | "True" | "TRUE" | "yes" | "Yes" | "YES" => json::Boolean(true),
+        "false" | "False" | "FALSE" | "no" | "No" | "NO" => json::Boolean(false),
+        _ => json::String(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use serialize::json;
+
+    fn yaml(input: &str) -> json::Json {
+        match parse(input) {
+            Ok(value) => value,
+            Err(e) => panic!("couldn't parse {}: {}", input, e),
+        }
+    }
+
+    fn json(input: &str) -> json::Json {
+        json::from_str(input).unwrap()
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(json("\"hello world\""), yaml("hello world"));
+        assert_eq!(json("\"50\""), yaml("50"));
+        assert_eq!(json("\"-1\""), yaml("-1"));
+        assert_eq!(json("\"1.0\""), yaml("1.0"));
+        assert_eq!(json("\"1.10\""), yaml("1.10"));
+        assert_eq!(json("true"), yaml("yes"));
+        assert_eq!(json("false"), yaml("false"));
+        assert_eq!(json("null"), yaml("~"));
+        assert_eq!(json("\"a # b\""), yaml("'a # b' # comment"));
+        assert_eq!(json("\"it's\""), yaml("'it''s'"));
+        assert_eq!(json("\"tab\\there\""), yaml("\"tab\\there\""));
+    }
+
+    #[test]
+    fn test_mapping() {
+        assert_eq!(json("{\"language\":\"rust\",\"git\":{\"depth\":\"10\",\"submodules\":false}}"), yaml("
+language: rust
+# a comment
+git:
+  depth: 10
+  submodules: false
+"));
+    }
+
+    #[test]
+    fn test_sequences() {
+        assert_eq!(json("{\"script\":[\"make\",\"make test\"],\"services\":[\"redis\"]}"), yaml("
+script:
+  - make
+  - make test
+services:
+- redis
+"));
+    }
+
+    #[test]
+    fn test_sequence_of_mappings() {
+        assert_eq!(json("{\"matrix\":{\"include\":[{\"rust\":\"nightly\",\"env\":\"FOO=bar\"},{\"rust\":\"beta\"}]}}"), yaml("
+matrix:
+  include:
+    - rust: nightly
+      env: FOO=bar
+    - rust: beta
+"));
+    }
+
+    #[test]
+    fn test_flow_collections() {
+        assert_eq!(json("{\"rust\":[\"stable\",\"1.0\"],\"git\":{\"depth\":\"1\"}}"), yaml("
+rust: [stable, 1.0]
+git: {depth: 1}
+"));
+    }
+
+    #[test]
+    fn test_block_scalars() {
+        assert_eq!(json("{\"script\":\"echo one\\necho two\\n\",\"after_script\":\"a b\"}"), yaml("
+script: |
+  echo one
+  echo two
+after_script: >-
+  a
+  b
+"));
+    }
+
+    #[test]
+    fn test_block_scalars_in_sequences() {
+        assert_eq!(json("{\"script\":[\"echo one\\necho two\\n\",\"a b\",\"make\"]}"), yaml("
+script:
+  - |
+    echo one
+    echo two
+  - >-
+    a
+    b
+  - make
+"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(3, parse("a: b\nc: d\n  e: f").err().unwrap().line);
+        assert_eq!(2, parse("a: b\na: c").err().unwrap().line);
+        assert_eq!(1, parse("a: \"unterminated").err().unwrap().line);
+    }
+}